@@ -0,0 +1,394 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::machine_components::SizeTable;
+use crate::message_based::{Cup, Machine, OrderError, OrderHandle, OrderStatus};
+use crate::recipe::Menu;
+
+/// Why a file of orders couldn't be read.
+#[derive(Debug)]
+pub enum BatchError {
+	/// The file couldn't be read.
+	Io { path: PathBuf, error: io::Error },
+	/// A line of the file isn't an order, counting from 1.
+	Invalid { line: usize, problem: String },
+}
+impl fmt::Display for BatchError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use BatchError::*;
+		match self {
+			Io { path, error } => write!(f, "Cannot read {}: {}", path.display(), error),
+			Invalid { line, problem } => write!(f, "line {}: {}", line, problem),
+		}
+	}
+}
+impl std::error::Error for BatchError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			BatchError::Io { error, .. } => Some(error),
+			BatchError::Invalid { .. } => None,
+		}
+	}
+}
+
+/// An order read from a file: who it's for, the drink and size by name, and
+/// when it arrived, if the file says. The line is where it is in the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchOrder {
+	pub line: usize,
+	pub customer: String,
+	pub drink: String,
+	pub size: String,
+	pub arrival: Option<Duration>,
+}
+
+/// What became of an order from a file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+	Ready,
+	/// The machine took the order but a stage failed to make it.
+	Failed(OrderError),
+	/// The machine never took the order, e.g. a drink that isn't on the menu
+	/// or a check that failed.
+	Rejected(String),
+}
+impl Outcome {
+	pub fn status(&self) -> &'static str {
+		match self {
+			Outcome::Ready => "ready",
+			Outcome::Failed(_) => "failed",
+			Outcome::Rejected(_) => "rejected",
+		}
+	}
+	pub fn reason(&self) -> Option<String> {
+		match self {
+			Outcome::Ready => None,
+			Outcome::Failed(e) => Some(e.to_string()),
+			Outcome::Rejected(reason) => Some(reason.clone()),
+		}
+	}
+}
+
+/// An order from a file and what became of it. Times are from the start of
+/// the replay, on the clock of the machine's Simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchResult {
+	pub order: BatchOrder,
+	/// The id the machine gave the order, if it took it.
+	pub id: Option<usize>,
+	pub outcome: Outcome,
+	pub arrived: Duration,
+	pub finished: Option<Duration>,
+}
+impl BatchResult {
+	/// How long the order took from arriving to being ready or failing.
+	pub fn took(&self) -> Option<Duration> {
+		self.finished.map(|finished| finished.saturating_sub(self.arrived))
+	}
+}
+
+/// Reads a file of orders, either CSV or JSON lines, e.g. a day's export
+/// from the till. See `parse_orders`.
+pub fn read_orders(path: impl AsRef<Path>) -> Result<Vec<BatchOrder>, BatchError> {
+	let path = path.as_ref();
+	let text = fs::read_to_string(path)
+		.map_err(|error| BatchError::Io { path: path.to_path_buf(), error })?;
+	parse_orders(&text)
+}
+
+/// Reads orders from CSV or JSON lines, whichever the text starts with.<br>
+/// CSV has a header naming its columns: customer, drink and size, and
+/// optionally arrival. Any other columns are ignored. JSON lines have an
+/// object on each line with the same keys.<br>
+/// An arrival is either seconds or a time of day, e.g. `90` or `08:15:30`.
+/// Only the time between arrivals matters, so a replay starts with the
+/// earliest order.
+pub fn parse_orders(text: &str) -> Result<Vec<BatchOrder>, BatchError> {
+	let json = text.lines().map(str::trim).find(|l| !l.is_empty()).is_some_and(|l| l.starts_with('{'));
+	if json { parse_json_lines(text) } else { parse_csv(text) }
+}
+
+fn invalid(line: usize, problem: &str) -> BatchError {
+	BatchError::Invalid { line, problem: problem.to_string() }
+}
+
+// Seconds, e.g. "90" or "1.5", or a time of day, e.g. "08:15" or "08:15:30".
+fn arrival(text: &str, line: usize) -> Result<Duration, BatchError> {
+	let bad = || invalid(line, &format!("\"{}\" is not an arrival, expected seconds or a time like 08:15:30", text));
+	let parts: Vec<&str> = text.trim().split(':').collect();
+	let seconds = match parts.as_slice() {
+		[s] => s.parse::<f64>().map_err(|_| bad())?,
+		[h, m] | [h, m, _] => {
+			let (h, m) = (h.parse::<u32>().map_err(|_| bad())?, m.parse::<u32>().map_err(|_| bad())?);
+			let s = parts.get(2).map_or(Ok(0.0), |s| s.parse::<f64>()).map_err(|_| bad())?;
+			if h > 23 || m > 59 || !(0.0..60.0).contains(&s) {
+				return Err(bad());
+			}
+			(h * 3600 + m * 60) as f64 + s
+		}
+		_ => return Err(bad()),
+	};
+	Duration::try_from_secs_f64(seconds).map_err(|_| bad())
+}
+
+// The fields of a line of CSV. Fields can be quoted, with a quote in a quoted
+// field written twice.
+fn csv_fields(line: &str) -> Vec<String> {
+	let (mut fields, mut field, mut quoted) = (Vec::new(), String::new(), false);
+	let mut chars = line.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' if quoted && chars.peek() == Some(&'"') => {
+				field.push('"');
+				chars.next();
+			}
+			'"' => quoted = !quoted,
+			',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+			c => field.push(c),
+		}
+	}
+	fields.push(field.trim().to_string());
+	fields
+}
+
+fn parse_csv(text: &str) -> Result<Vec<BatchOrder>, BatchError> {
+	let mut lines = text.lines().enumerate().map(|(i, l)| (i + 1, l)).filter(|(_, l)| !l.trim().is_empty());
+	let (header_line, header) = match lines.next() {
+		Some((n, l)) => (n, csv_fields(l)),
+		None => return Ok(Vec::new()),
+	};
+	let column = |name: &str| header.iter().position(|h| h.eq_ignore_ascii_case(name));
+	let required = |name: &str| column(name).ok_or_else(|| invalid(header_line, &format!("no {} column in the header", name)));
+	let (customer, drink, size, arrives) = (required("customer")?, required("drink")?, required("size")?, column("arrival"));
+	lines
+		.map(|(line, l)| {
+			let fields = csv_fields(l);
+			let field = |i: usize, name: &str| match fields.get(i) {
+				Some(f) if !f.is_empty() => Ok(f.clone()),
+				_ => Err(invalid(line, &format!("missing {}", name))),
+			};
+			Ok(BatchOrder {
+				line,
+				customer: field(customer, "customer")?,
+				drink: field(drink, "drink")?,
+				size: field(size, "size")?,
+				arrival: match arrives.and_then(|i| fields.get(i)).filter(|f| !f.is_empty()) {
+					Some(f) => Some(arrival(f, line)?),
+					None => None,
+				},
+			})
+		})
+		.collect()
+}
+
+fn parse_json_lines(text: &str) -> Result<Vec<BatchOrder>, BatchError> {
+	text.lines().enumerate()
+		.map(|(i, l)| (i + 1, l.trim()))
+		.filter(|(_, l)| !l.is_empty())
+		.map(|(line, l)| {
+			let v: Value = serde_json::from_str(l).map_err(|e| invalid(line, &e.to_string()))?;
+			let field = |name: &str| match v.get(name) {
+				Some(Value::String(s)) if !s.trim().is_empty() => Ok(s.trim().to_string()),
+				Some(Value::String(_)) | None => Err(invalid(line, &format!("missing {}", name))),
+				Some(other) => Err(invalid(line, &format!("{} should be a string, found {}", name, other))),
+			};
+			Ok(BatchOrder {
+				line,
+				customer: field("customer")?,
+				drink: field("drink")?,
+				size: field("size")?,
+				arrival: match v.get("arrival") {
+					None | Some(Value::Null) => None,
+					Some(Value::String(s)) => Some(arrival(s, line)?),
+					Some(Value::Number(n)) => Some(arrival(&n.to_string(), line)?),
+					Some(other) => return Err(invalid(line, &format!("arrival should be seconds or a time, found {}", other))),
+				},
+			})
+		})
+		.collect()
+}
+
+/// Replays orders against a machine, submitting each one when it arrived,
+/// i.e. as long after the earliest order as it was in the file, and then
+/// waits for every order to finish. Orders without an arrival are submitted
+/// straight after the order before them. Drinks and sizes are looked up on
+/// the menu and size table given.<br>
+/// On the wall clock a replay takes as long as the orders took to arrive. On
+/// a seeded simulation's virtual clock it takes no time, but the waits
+/// between arrivals move the clock on while the machine works, so the times
+/// of orders that overlap are only a rough guide.
+pub fn replay(machine: &Machine, menu: &Menu, sizes: &SizeTable, orders: &[BatchOrder]) -> Vec<BatchResult> {
+	let clock = Arc::clone(machine.settings().simulation.clock());
+	let start = clock.now();
+	let first = orders.iter().filter_map(|o| o.arrival).min().unwrap_or_default();
+	// the handles are held until the results are read, so that the machine
+	// keeps every order of the replay.
+	let submitted: Vec<(usize, Result<OrderHandle, String>, Duration)> = orders.iter().enumerate()
+		.map(|(i, order)| {
+			if let Some(due) = order.arrival.map(|a| start + (a - first)) {
+				let now = clock.now();
+				if due > now {
+					clock.sleep(due - now);
+				}
+			}
+			let arrived = clock.now() - start;
+			let cup = sizes.get(&order.size)
+				.ok_or_else(|| format!("{} is not a size on offer", order.size))
+				.and_then(|s| Cup::order(menu, &order.drink, s, order.customer.clone())
+					.ok_or_else(|| format!("{} is not on the menu", order.drink)));
+			let handle = cup.and_then(|cup| machine.submit(cup).map_err(|e| e.to_string()));
+			(i, handle, arrived)
+		})
+		.collect();
+	machine.wait_all();
+	submitted.into_iter()
+		.map(|(i, handle, arrived)| {
+			let order = orders[i].clone();
+			match handle {
+				Err(reason) => BatchResult { order, id: None, outcome: Outcome::Rejected(reason), arrived, finished: None },
+				Ok(handle) => {
+					let id = handle.id();
+					let outcome = match handle.status() {
+						OrderStatus::Failed(e) => Outcome::Failed(e),
+						_ => Outcome::Ready,
+					};
+					let finished = machine.timing(id).and_then(|t| t.finished).map(|f| f.saturating_sub(start));
+					BatchResult { order, id: Some(id), outcome, arrived, finished }
+				}
+			}
+		})
+		.collect()
+}
+
+/// Writes the results of a replay to a file, as JSON lines if the file ends
+/// in .jsonl, .ndjson or .json and as CSV otherwise.
+pub fn write_results(path: impl AsRef<Path>, results: &[BatchResult]) -> io::Result<()> {
+	let path = path.as_ref();
+	let json = path.extension().and_then(|e| e.to_str())
+		.is_some_and(|e| ["jsonl", "ndjson", "json"].iter().any(|j| e.eq_ignore_ascii_case(j)));
+	fs::write(path, if json { results_json_lines(results) } else { results_csv(results) })
+}
+
+fn millis(d: Option<Duration>) -> Option<u128> {
+	d.map(|d| d.as_millis())
+}
+
+// A field of CSV, quoted if it needs to be.
+fn csv_field(field: &str) -> String {
+	if field.contains([',', '"', '\n']) {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_string()
+	}
+}
+
+/// The results of a replay as CSV, with a row for each order giving its
+/// status, why it failed if it did, and when it arrived and finished in
+/// milliseconds.
+pub fn results_csv(results: &[BatchResult]) -> String {
+	let mut csv = String::from("line,customer,drink,size,order,status,reason,arrived_ms,finished_ms,took_ms\n");
+	let text = |v: Option<u128>| v.map(|v| v.to_string()).unwrap_or_default();
+	for r in results {
+		let row = [
+			r.order.line.to_string(),
+			csv_field(&r.order.customer),
+			csv_field(&r.order.drink),
+			csv_field(&r.order.size),
+			r.id.map(|id| id.to_string()).unwrap_or_default(),
+			r.outcome.status().to_string(),
+			csv_field(&r.outcome.reason().unwrap_or_default()),
+			r.arrived.as_millis().to_string(),
+			text(millis(r.finished)),
+			text(millis(r.took())),
+		];
+		csv.push_str(&row.join(","));
+		csv.push('\n');
+	}
+	csv
+}
+
+/// The results of a replay as JSON lines, with the same fields as
+/// `results_csv`.
+pub fn results_json_lines(results: &[BatchResult]) -> String {
+	results.iter()
+		.map(|r| json!({
+			"line": r.order.line,
+			"customer": r.order.customer,
+			"drink": r.order.drink,
+			"size": r.order.size,
+			"order": r.id,
+			"status": r.outcome.status(),
+			"reason": r.outcome.reason(),
+			"arrived_ms": r.arrived.as_millis() as u64,
+			"finished_ms": millis(r.finished).map(|m| m as u64),
+			"took_ms": millis(r.took()).map(|m| m as u64),
+		}).to_string() + "\n")
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The line and problem of orders that can't be read.
+	fn refused(text: &str) -> (usize, String) {
+		match parse_orders(text) {
+			Err(BatchError::Invalid { line, problem }) => (line, problem),
+			Err(e) => panic!("refused for another reason: {}", e),
+			Ok(orders) => panic!("not refused, read {:?}", orders),
+		}
+	}
+
+	#[test]
+	fn reads_csv_by_its_header() {
+		let text = "Size,Customer,Drink,Arrival,Till\n\nLarge,Josh,Latte,90,1\nSmall,\"Mary, Jr.\",\"The \"\"Usual\"\"\",08:15:30,2\nMedium,Tosh,Doppio,,3\n";
+		let orders = parse_orders(text).unwrap();
+		assert_eq!(orders.len(), 3);
+		assert_eq!(orders[0], BatchOrder {
+			line: 3,
+			customer: "Josh".to_string(),
+			drink: "Latte".to_string(),
+			size: "Large".to_string(),
+			arrival: Some(Duration::from_secs(90)),
+		});
+		assert_eq!((orders[1].customer.as_str(), orders[1].drink.as_str()), ("Mary, Jr.", "The \"Usual\""));
+		assert_eq!(orders[1].arrival, Some(Duration::from_secs(8 * 3600 + 15 * 60 + 30)));
+		assert_eq!((orders[2].line, orders[2].arrival), (5, None));
+	}
+
+	#[test]
+	fn reads_json_lines() {
+		let text = "{\"customer\": \"Josh\", \"drink\": \"Latte\", \"size\": \"Large\", \"arrival\": 1.5}\n\n{\"customer\": \"Mary\", \"drink\": \"Doppio\", \"size\": \"Small\", \"arrival\": \"08:15\"}\n";
+		let orders = parse_orders(text).unwrap();
+		assert_eq!(orders.iter().map(|o| o.line).collect::<Vec<_>>(), vec![1, 3]);
+		assert_eq!(orders[0].arrival, Some(Duration::from_millis(1500)));
+		assert_eq!(orders[1].arrival, Some(Duration::from_secs(8 * 3600 + 15 * 60)));
+	}
+
+	#[test]
+	fn errors_give_the_line_counting_from_one() {
+		assert_eq!(refused("\ncustomer,drink\nJosh,Latte\n"), (2, "no size column in the header".to_string()));
+		assert_eq!(refused("customer,drink,size\nJosh,Latte,Large\nMary,,Small\n"), (3, "missing drink".to_string()));
+		assert_eq!(refused("customer,drink,size\nJosh,Latte\n"), (2, "missing size".to_string()));
+		let (line, problem) = refused("customer,drink,size,arrival\nJosh,Latte,Large,25:00\n");
+		assert_eq!(line, 2);
+		assert!(problem.starts_with("\"25:00\" is not an arrival"), "{}", problem);
+		assert_eq!(refused("customer,drink,size,arrival\nJosh,Latte,Large,-1\n").0, 2);
+		assert_eq!(refused("customer,drink,size,arrival\nJosh,Latte,Large,1e300\n").0, 2);
+		assert_eq!(refused("customer,drink,size,arrival\nJosh,Latte,Large,NaN\n").0, 2);
+		assert_eq!(refused("{\"customer\": \"Josh\", \"drink\": \"Latte\", \"size\": \"Large\", \"arrival\": 1e300}\n").0, 1);
+		assert_eq!(refused("{\"customer\": \"Josh\", \"drink\": \"Latte\", \"size\": \"Large\"}\n{\"customer\": \"Mary\"").0, 2);
+		assert_eq!(refused("{\"customer\": \"Josh\", \"drink\": \"Latte\", \"size\": 16}\n"), (1, "size should be a string, found 16".to_string()));
+		assert_eq!(refused("{\"customer\": \" \", \"drink\": \"Latte\", \"size\": \"Large\"}\n"), (1, "missing customer".to_string()));
+	}
+
+	#[test]
+	fn errors_read_as_the_line_and_the_problem() {
+		let e = parse_orders("customer,drink\n").unwrap_err();
+		assert_eq!(e.to_string(), "line 1: no size column in the header");
+	}
+}
@@ -1,51 +1,272 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::machine_components::{Component, Ingredient, MachineError, Milk, Size};
+use crate::message_based::{check_inventory, Cup};
+use crate::recipe::{Measure, Menu, Portion, Recipe, Step};
+use crate::units::Volume;
+
+/// The reasons an EspressoBuilder can refuse to build a cup.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    /// No ingredients were added to the cup.
+    Empty,
+    /// The ingredients don't fit in the cup, e.g. four shots of espresso and
+    /// a pump of syrup in a 4 oz. cup.
+    Overfull { size: Size, volume: f32 },
+    /// More than one kind of milk was added. A cup is made with one kind.
+    MixedMilk(Milk, Milk),
+    /// The preset drink isn't on the menu.
+    UnknownDrink(String),
+    /// The machine doesn't hold enough of an ingredient.
+    Inventory(MachineError),
+}
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BuildError::*;
+        match self {
+            Empty => write!(f, "No ingredients added to the cup"),
+            Overfull { size, volume } => write!(
+                f, "{} of ingredients does not fit in a {} cup", Volume::fluid_ounces(*volume), size
+            ),
+            MixedMilk(a, b) => write!(f, "Cannot make a cup with both {} and {}", a, b),
+            UnknownDrink(name) => write!(f, "{} is not on the menu", name),
+            Inventory(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for BuildError {}
+
+/// Builds a Cup for an order one ingredient at a time, e.g.
+/// `EspressoBuilder::new().set_name("Josh").add_espresso().add_milk().build()`.
+/// Each espresso is a shot and each syrup a pump, and whatever room is left
+/// in the cup is shared between the milk, foam and hot water.<br>
+/// The ingredients are checked against the size of the cup when the cup is
+/// built, and against the machine's inventory if one was given.
 pub struct EspressoBuilder {
-    cup: Cup,
     ingredients: Vec<Ingredient>,
     customer_name: String,
-    size: Size
+    drink_name: String,
+    size: Size,
+    inventory: Vec<(Component, Measure)>,
+}
+
+impl Default for EspressoBuilder {
+    fn default() -> Self {
+        EspressoBuilder::new()
+    }
 }
 
 impl EspressoBuilder {
-    fn add_ingredient(mut self, ingredient: Ingredient) -> Self {
+    pub fn new() -> Self {
+        EspressoBuilder {
+            ingredients: Vec::new(),
+            customer_name: String::new(),
+            drink_name: "Custom Drink".to_string(),
+            size: Size::MEDIUM,
+            inventory: Vec::new(),
+        }
+    }
+
+    pub fn add_ingredient(mut self, ingredient: Ingredient) -> Self {
         self.ingredients.push(ingredient);
         self
     }
 
-    fn set_name(mut self, customer_name: &String) -> Self {
-        self.cup.name = customer_name;
+    pub fn set_name(mut self, customer_name: &str) -> Self {
+        self.customer_name = customer_name.to_string();
+        self
+    }
+
+    pub fn set_drink_name(mut self, drink_name: &str) -> Self {
+        self.drink_name = drink_name.to_string();
         self
     }
 
-    fn set_cup_size(mut self, size: Size) -> Self {
+    pub fn set_cup_size(mut self, size: Size) -> Self {
         self.size = size;
         self
     }
 
-    fn add_espresso(mut self) -> Self {
+    /// Checks the cup against the material held by the given components when
+    /// it is built, e.g. the components of a running machine.
+    pub fn check_inventory(mut self, components: &[(Component, Measure)]) -> Self {
+        self.inventory = components.to_vec();
+        self
+    }
+
+    pub fn add_espresso(mut self) -> Self {
         self.ingredients.push(Ingredient::Espresso);
         self
     }
 
-    fn add_milk(mut self) -> Self {
-        self.ingredients.push(Ingredient::Milk);
+    pub fn add_milk(mut self) -> Self {
+        self.ingredients.push(Ingredient::Milk(Milk::Whole));
         self
     }
 
-    fn build(mut self) -> Cup {
-        Cup {
-            size: self.size,
-            contents: self.ingredients,
-            client: self.customer_name
+    pub fn build(self) -> Result<Cup, BuildError> {
+        let recipe = self.recipe()?;
+        self.finish(recipe)
+    }
+
+    pub fn build_latte(self) -> Result<Cup, BuildError> {
+        self.build_preset("Latte")
+    }
+
+    pub fn build_cappuccino(self) -> Result<Cup, BuildError> {
+        self.build_preset("Cappuccino")
+    }
+
+    pub fn build_americano(self) -> Result<Cup, BuildError> {
+        self.build_preset("Americano")
+    }
+
+    pub fn build_espresso(self) -> Result<Cup, BuildError> {
+        self.build_preset("Espresso")
+    }
+
+    /// Builds a cup of a drink from the standard menu, ignoring any
+    /// ingredients already added.
+    pub fn build_preset(self, drink: &str) -> Result<Cup, BuildError> {
+        let recipe = Menu::standard().get(drink)
+            .ok_or_else(|| BuildError::UnknownDrink(drink.to_string()))?;
+        self.finish((*recipe).clone())
+    }
+
+    // Turns the ingredients into a recipe for the size of cup. Shots of
+    // espresso and pumps of syrup take up a fixed amount of the cup, and the
+    // milk, foam and hot water split what is left between them.
+    fn recipe(&self) -> Result<Recipe, BuildError> {
+        if self.ingredients.is_empty() {
+            return Err(BuildError::Empty);
+        }
+        let mut milk: Option<Milk> = None;
+        for i in &self.ingredients {
+            if let Ingredient::Milk(m) | Ingredient::Foam(m) = i {
+                match milk {
+                    Some(kind) if kind != *m => return Err(BuildError::MixedMilk(kind, *m)),
+                    _ => milk = Some(*m),
+                }
+            }
+        }
+        let shots = self.ingredients.iter().filter(|i| **i == Ingredient::Espresso).count();
+        let mut recipe = Recipe::new(&self.drink_name).with_milk(milk.unwrap_or(Milk::Whole));
+        if shots > 0 {
+            recipe = recipe.branch(vec![Step::Espresso(Portion::each(shots as f32))]);
+        }
+        for i in &self.ingredients {
+            if let Ingredient::Syrup(s) = i {
+                recipe = recipe.with_syrup(*s);
+            }
         }
+        let liquids = self.ingredients.iter()
+            .filter(|i| matches!(i, Ingredient::Milk(_) | Ingredient::Foam(_) | Ingredient::HotWater))
+            .count();
+        let room = self.size.ounces() - recipe.volume(self.size);
+        if room < 0.0 {
+            return Err(BuildError::Overfull { size: self.size, volume: recipe.volume(self.size) });
+        }
+        if liquids > 0 {
+            let share = Portion::each(room / liquids as f32);
+            let milk_steps: Vec<Step> = self.ingredients.iter()
+                .filter_map(|i| match i {
+                    Ingredient::Milk(_) => Some(Step::SteamedMilk(share)),
+                    Ingredient::Foam(_) => Some(Step::Foam(share)),
+                    _ => None,
+                })
+                .collect();
+            if !milk_steps.is_empty() {
+                recipe = recipe.branch(milk_steps);
+            }
+            let water = self.ingredients.iter().filter(|i| **i == Ingredient::HotWater).count();
+            if water > 0 {
+                recipe = recipe.branch(vec![Step::HotWater(Portion::each(share.small * water as f32))]);
+            }
+        }
+        Ok(recipe)
     }
 
-    fn build_latte(mut self, name: &String, size: Size) -> Cup {
-        self.ingredients.push(Ingredient::Espresso);
-        self.ingredients.push(Ingredient::Milk);
-        Cup {
-            size,
-            client: name,
-            contents: self.ingredients
+    // Checks the recipe fits in the cup and that the machine holds enough of
+    // it, then makes the cup.
+    fn finish(self, recipe: Recipe) -> Result<Cup, BuildError> {
+        let volume = recipe.volume(self.size);
+        // leave a little room for rounding in the portions.
+        if volume > self.size.ounces() + 0.01 {
+            return Err(BuildError::Overfull { size: self.size, volume });
         }
+        if !self.inventory.is_empty() {
+            check_inventory(&self.inventory, self.size, &recipe).map_err(BuildError::Inventory)?;
+        }
+        Ok(Cup::new(self.size, Arc::new(recipe), self.customer_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine_components::{MilkTank, Syrup};
+
+    #[test]
+    fn builds_a_latte_from_its_ingredients() {
+        let cup = EspressoBuilder::new()
+            .set_name("Josh")
+            .set_drink_name("Josh's Latte")
+            .add_espresso()
+            .add_milk()
+            .build()
+            .unwrap();
+        assert_eq!(cup.client(), "Josh");
+        assert_eq!(cup.size(), Size::MEDIUM);
+        assert_eq!(cup.recipe().name(), "Josh's Latte");
+        assert_eq!(cup.recipe().ingredients(), vec![Ingredient::Espresso, Ingredient::Milk(Milk::Whole)]);
+        // the milk fills what the shot leaves of the cup.
+        assert_eq!(cup.recipe().volume(Size::MEDIUM), 12.0);
+    }
+
+    #[test]
+    fn refuses_an_empty_cup() {
+        assert_eq!(EspressoBuilder::new().build().err(), Some(BuildError::Empty));
+    }
+
+    #[test]
+    fn refuses_two_kinds_of_milk() {
+        let err = EspressoBuilder::new()
+            .add_ingredient(Ingredient::Milk(Milk::Oat))
+            .add_ingredient(Ingredient::Foam(Milk::Skim))
+            .build()
+            .err().unwrap();
+        assert_eq!(err, BuildError::MixedMilk(Milk::Oat, Milk::Skim));
+    }
+
+    #[test]
+    fn refuses_more_than_fits_in_the_cup() {
+        let err = EspressoBuilder::new()
+            .set_cup_size(Size::oz(4.0))
+            .add_espresso()
+            .add_espresso()
+            .add_espresso()
+            .add_espresso()
+            .add_ingredient(Ingredient::Syrup(Syrup::Vanilla))
+            .build()
+            .err().unwrap();
+        assert!(matches!(err, BuildError::Overfull { size, .. } if size == Size::oz(4.0)), "{:?}", err);
+    }
+
+    #[test]
+    fn presets_come_from_the_menu() {
+        let cup = EspressoBuilder::new().set_cup_size(Size::LARGE).build_cappuccino().unwrap();
+        assert_eq!(cup.recipe().name(), "Cappuccino");
+        let err = EspressoBuilder::new().build_preset("Frappuccino").err().unwrap();
+        assert_eq!(err, BuildError::UnknownDrink("Frappuccino".to_string()));
+    }
+
+    #[test]
+    fn refuses_what_the_machine_does_not_hold() {
+        let tank = Component::Container(Arc::new(MilkTank::with_level(Milk::Whole, 2.0)));
+        let err = EspressoBuilder::new()
+            .check_inventory(&[(tank, Measure::Milk(Milk::Whole))])
+            .build_latte()
+            .err().unwrap();
+        assert!(matches!(err, BuildError::Inventory(_)), "{:?}", err);
     }
 }
@@ -0,0 +1,560 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use toml::value::{Table, Value};
+use crate::ingredient_based::Temperatures;
+use crate::machine_components::*;
+use crate::message_based::{Machine, Settings};
+use crate::recipe::{Dose, Measure, Menu, Portion, Step};
+use crate::shift::{Stage, Timings};
+use crate::units::{self, Temperature, UnitSystem};
+
+/// Why a machine definition couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+	/// The file couldn't be read.
+	Io { path: PathBuf, error: io::Error },
+	/// The file isn't valid TOML.
+	Syntax(toml::de::Error),
+	/// A key is missing, unknown or has a value the machine can't be built
+	/// with, e.g. a tank that starts with more milk than it holds. The key is
+	/// given as a path from the top of the file, e.g. `components[2].level`.
+	Invalid { key: String, problem: String },
+}
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use ConfigError::*;
+		match self {
+			Io { path, error } => write!(f, "Cannot read {}: {}", path.display(), error),
+			Syntax(e) => write!(f, "Invalid machine definition: {}", e),
+			Invalid { key, problem } => write!(f, "{}: {}", key, problem),
+		}
+	}
+}
+impl std::error::Error for ConfigError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			ConfigError::Io { error, .. } => Some(error),
+			ConfigError::Syntax(e) => Some(e),
+			ConfigError::Invalid { .. } => None,
+		}
+	}
+}
+
+/// A component of a machine and what it is built with. Hoppers, tanks and
+/// syrup bottles are given in ounces.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ComponentConfig {
+	CoffeeHopper { capacity: f32, level: f32 },
+	WaterTank { capacity: f32, level: f32 },
+	EspressoPress,
+	Frother,
+	MilkTank { milk: Milk, capacity: f32, level: f32 },
+	SyrupPump { syrup: Syrup, capacity: f32, level: f32 },
+}
+impl ComponentConfig {
+	/// Builds the component, paired with the Measure of how much of its job a
+	/// recipe needs.
+	pub fn build(&self) -> (Component, Measure) {
+		match *self {
+			ComponentConfig::CoffeeHopper { capacity, level } =>
+				(Component::Container(Arc::new(CoffeeHopper::with_capacity(capacity, level))), Measure::Beans),
+			ComponentConfig::WaterTank { capacity, level } =>
+				(Component::Container(Arc::new(WaterTank::with_capacity(capacity, level))), Measure::Water),
+			ComponentConfig::EspressoPress => (Component::Device(Arc::new(EspressoPress)), Measure::Shots),
+			ComponentConfig::Frother => (Component::Device(Arc::new(Frother)), Measure::Foam),
+			ComponentConfig::MilkTank { milk, capacity, level } =>
+				(Component::Container(Arc::new(MilkTank::with_capacity(milk, capacity, level))), Measure::Milk(milk)),
+			ComponentConfig::SyrupPump { syrup, capacity, level } =>
+				(Component::Container(Arc::new(SyrupPump::with_capacity(syrup, capacity, level))), Measure::Syrup(syrup)),
+		}
+	}
+	/// The name the component goes by, e.g. "OatMilkTank".
+	pub fn name(&self) -> &'static str {
+		self.build().0.name()
+	}
+}
+
+/// Everything a store's machine is built with: its components and their
+/// capacities, the timeouts it pings them with, the dose of each shot, the
+/// portions of milk, foam and hot water in its drinks, the sizes of cup on
+/// offer, how long each stage takes, the temperatures it makes drinks at and
+/// the units it shows quantities in. A machine definition is a TOML file, e.g.
+/// `MachineConfig::load("machine.toml")?.start()`, where every section can be
+/// left out for the standard machine's. See machine.toml at the top of the
+/// repository for every key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineConfig {
+	pub components: Vec<ComponentConfig>,
+	pub timeouts: Timeouts,
+	pub dose: Dose,
+	pub menu: Menu,
+	pub sizes: SizeTable,
+	pub timings: Timings,
+	pub temperatures: Temperatures,
+	pub units: UnitSystem,
+}
+impl Default for MachineConfig {
+	fn default() -> Self {
+		MachineConfig::standard()
+	}
+}
+impl MachineConfig {
+	/// The standard machine: a full hopper, water tank and milk tank of every
+	/// kind of milk at 64 oz. each, a full 25 oz. bottle of every syrup, and
+	/// the standard timeouts, dose, menu, sizes, timings and temperatures, in
+	/// imperial units.
+	pub fn standard() -> Self {
+		let mut components = vec![
+			ComponentConfig::CoffeeHopper { capacity: CAPACITY, level: CAPACITY },
+			ComponentConfig::WaterTank { capacity: CAPACITY, level: CAPACITY },
+			ComponentConfig::EspressoPress,
+			ComponentConfig::Frother,
+		];
+		components.extend(Milk::ALL.iter().map(|milk| ComponentConfig::MilkTank { milk: *milk, capacity: CAPACITY, level: CAPACITY }));
+		components.extend(Syrup::ALL.iter().map(|syrup| ComponentConfig::SyrupPump { syrup: *syrup, capacity: SYRUP_CAPACITY, level: SYRUP_CAPACITY }));
+		MachineConfig {
+			components,
+			timeouts: Timeouts::default(),
+			dose: Dose::STANDARD,
+			menu: Menu::standard(),
+			sizes: SizeTable::standard(),
+			timings: Timings::default(),
+			temperatures: Temperatures::STANDARD,
+			units: UnitSystem::Imperial,
+		}
+	}
+
+	/// Reads a machine definition from a TOML file.
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+		let path = path.as_ref();
+		let text = fs::read_to_string(path)
+			.map_err(|error| ConfigError::Io { path: path.to_path_buf(), error })?;
+		MachineConfig::from_toml(&text)
+	}
+
+	/// Reads a machine definition from TOML text. Sections that are left out
+	/// are the standard machine's, and unknown keys are refused so that a
+	/// misspelt key isn't silently ignored.
+	pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+		let root: Table = toml::from_str(text).map_err(ConfigError::Syntax)?;
+		known(&root, "", &["units", "components", "timeouts", "dose", "menu", "sizes", "stages", "temperatures"])?;
+		let mut config = MachineConfig::standard();
+		if let Some(value) = root.get("units") {
+			config.units = unit_system(value)?;
+		}
+		if let Some(value) = root.get("components") {
+			config.components = components(value)?;
+		}
+		if let Some(value) = root.get("timeouts") {
+			config.timeouts = timeouts(value, &config.components)?;
+		}
+		if let Some(value) = root.get("dose") {
+			let t = table(value, "dose")?;
+			known(t, "dose", &["beans_per_shot", "water_per_shot"])?;
+			if let Some(v) = t.get("beans_per_shot") {
+				config.dose.beans_per_shot = positive(v, "dose.beans_per_shot")?;
+			}
+			if let Some(v) = t.get("water_per_shot") {
+				config.dose.water_per_shot = positive(v, "dose.water_per_shot")?;
+			}
+		}
+		if let Some(value) = root.get("menu") {
+			config.menu = menu(value, config.menu)?;
+		}
+		if let Some(value) = root.get("sizes") {
+			let t = table(value, "sizes")?;
+			if t.is_empty() {
+				return Err(invalid("sizes", "at least one size of cup is needed"));
+			}
+			config.sizes = t.iter().try_fold(SizeTable::new(), |sizes, (name, v)| {
+				Ok(sizes.with(name, Size::oz(positive(v, &format!("sizes.{}", name))?)))
+			})?;
+		}
+		if let Some(value) = root.get("stages") {
+			let t = table(value, "stages")?;
+			for (name, v) in t {
+				let key = format!("stages.{}", name);
+				let stage = Stage::ALL.iter().find(|s| s.name() == name)
+					.ok_or_else(|| invalid(&key, &format!("not a stage, expected one of {}", list(Stage::ALL.iter().map(|s| s.name())))))?;
+				config.timings = config.timings.with(*stage, Duration::from_millis(millis(v, &key)? as u64));
+			}
+		}
+		if let Some(value) = root.get("temperatures") {
+			let t = table(value, "temperatures")?;
+			known(t, "temperatures", &["room", "fridge", "brew", "steam"])?;
+			let temps = &mut config.temperatures;
+			for (name, temp) in [("room", &mut temps.room), ("fridge", &mut temps.fridge), ("brew", &mut temps.brew), ("steam", &mut temps.steam)] {
+				if let Some(v) = t.get(name) {
+					*temp = temperature(v, &format!("temperatures.{}", name))?;
+				}
+			}
+		}
+		Ok(config)
+	}
+
+	/// Builds the machine's components, each paired with the Measure of how
+	/// much of its job a recipe needs. Every call builds new components, so
+	/// two machines started from one definition don't share their tanks.
+	pub fn components(&self) -> Vec<(Component, Measure)> {
+		self.components.iter().map(|c| c.build()).collect()
+	}
+
+	/// The Settings a machine built from the definition runs with.
+	pub fn settings(&self) -> Settings {
+		Settings { timeouts: self.timeouts.clone(), ..Settings::default() }
+	}
+
+	/// The machine's menu, made with its dose.
+	pub fn menu(&self) -> Menu {
+		self.menu.clone().with_dose(self.dose)
+	}
+
+	/// Starts a machine from the definition, and shows quantities in its
+	/// units from then on.
+	pub fn start(&self) -> Machine {
+		units::set_unit_system(self.units);
+		Machine::start_with(self.components(), self.settings())
+	}
+}
+
+fn invalid(key: &str, problem: &str) -> ConfigError {
+	ConfigError::Invalid { key: key.to_string(), problem: problem.to_string() }
+}
+
+// A key below another, e.g. "timeouts" and "default" make "timeouts.default".
+fn path(parent: &str, key: &str) -> String {
+	if parent.is_empty() { key.to_string() } else { format!("{}.{}", parent, key) }
+}
+
+fn list<'a>(names: impl Iterator<Item = &'a str>) -> String {
+	names.collect::<Vec<_>>().join(", ")
+}
+
+// Refuses any key of the table that isn't one of the known keys.
+fn known(t: &Table, parent: &str, keys: &[&str]) -> Result<(), ConfigError> {
+	match t.keys().find(|k| !keys.contains(&k.as_str())) {
+		Some(k) => Err(invalid(&path(parent, k), &format!("unknown key, expected one of {}", list(keys.iter().copied())))),
+		None => Ok(()),
+	}
+}
+
+fn table<'a>(v: &'a Value, key: &str) -> Result<&'a Table, ConfigError> {
+	v.as_table().ok_or_else(|| invalid(key, &format!("expected a table, found {}", v.type_str())))
+}
+
+fn text<'a>(v: &'a Value, key: &str) -> Result<&'a str, ConfigError> {
+	v.as_str().ok_or_else(|| invalid(key, &format!("expected a string, found {}", v.type_str())))
+}
+
+fn number(v: &Value, key: &str) -> Result<f32, ConfigError> {
+	match v {
+		Value::Integer(i) => Ok(*i as f32),
+		Value::Float(f) if (*f as f32).is_finite() => Ok(*f as f32),
+		Value::Float(f) => Err(invalid(key, &format!("expected a number, found {}", f))),
+		_ => Err(invalid(key, &format!("expected a number, found {}", v.type_str()))),
+	}
+}
+
+fn positive(v: &Value, key: &str) -> Result<f32, ConfigError> {
+	match number(v, key)? {
+		n if n > 0.0 && n.is_finite() => Ok(n),
+		n => Err(invalid(key, &format!("expected more than 0, found {}", n))),
+	}
+}
+
+fn millis(v: &Value, key: &str) -> Result<usize, ConfigError> {
+	match v {
+		Value::Integer(i) if *i >= 0 => Ok(*i as usize),
+		Value::Integer(i) => Err(invalid(key, &format!("expected milliseconds of 0 or more, found {}", i))),
+		_ => Err(invalid(key, &format!("expected whole milliseconds, found {}", v.type_str()))),
+	}
+}
+
+fn unit_system(v: &Value) -> Result<UnitSystem, ConfigError> {
+	match text(v, "units")?.trim().to_lowercase().as_str() {
+		"imperial" => Ok(UnitSystem::Imperial),
+		"metric" => Ok(UnitSystem::Metric),
+		other => Err(invalid("units", &format!("unknown units \"{}\", expected imperial or metric", other))),
+	}
+}
+
+// Temperatures are given in degrees Fahrenheit as a number, or as a string
+// with their scale, e.g. "93 °C" or "200 F".
+fn temperature(v: &Value, key: &str) -> Result<Temperature, ConfigError> {
+	let temp = match v {
+		Value::String(s) => {
+			let s = s.trim();
+			let degrees = s.trim_end_matches(|c: char| c.is_alphabetic() || c == '°').trim_end();
+			let scale = s[degrees.len()..].trim_start().trim_start_matches('°');
+			let n: f32 = degrees.parse().ok().filter(|n: &f32| n.is_finite())
+				.ok_or_else(|| invalid(key, &format!("expected degrees such as \"93 °C\" or \"200 °F\", found \"{}\"", s)))?;
+			match scale.to_uppercase().as_str() {
+				"C" => Temperature::celsius(n),
+				"F" | "" => Temperature::fahrenheit(n),
+				_ => return Err(invalid(key, &format!("unknown scale \"{}\", expected °C or °F", scale))),
+			}
+		}
+		_ => Temperature::fahrenheit(number(v, key)?),
+	};
+	match temp.as_fahrenheit() {
+		f if f >= -459.67 => Ok(temp),
+		_ => Err(invalid(key, &format!("{} is below absolute zero", temp))),
+	}
+}
+
+// An amount of each size of cup, either one number for every size or an array
+// of the small, medium and large amounts.
+fn portion(v: &Value, key: &str) -> Result<Portion, ConfigError> {
+	match v {
+		Value::Array(a) if a.len() == 3 => Ok(Portion::new(
+			positive(&a[0], &format!("{}[0]", key))?,
+			positive(&a[1], &format!("{}[1]", key))?,
+			positive(&a[2], &format!("{}[2]", key))?,
+		)),
+		Value::Array(a) => Err(invalid(key, &format!("expected the small, medium and large amounts, found {} amounts", a.len()))),
+		_ => Ok(Portion::each(positive(v, key)?)),
+	}
+}
+
+// The portions of the drinks on the menu, by drink. Only the steps a drink
+// already has can be portioned, e.g. an americano has no milk.
+fn menu(value: &Value, mut menu: Menu) -> Result<Menu, ConfigError> {
+	const STEPS: [&str; 3] = ["milk", "foam", "hot_water"];
+	let portions = |key: &str, step: &Step| matches!(
+		(key, step),
+		("milk", Step::SteamedMilk(_)) | ("foam", Step::Foam(_)) | ("hot_water", Step::HotWater(_))
+	);
+	for (drink, v) in table(value, "menu")? {
+		let parent = format!("menu.{}", drink);
+		let mut recipe = menu.recipe(drink)
+			.ok_or_else(|| invalid(&parent, &format!("not on the menu, expected one of {}", list(menu.names()))))
+			.map(|r| (*r).clone())?;
+		let t = table(v, &parent)?;
+		known(t, &parent, &STEPS)?;
+		for step in STEPS {
+			if let Some(v) = t.get(step) {
+				let key = path(&parent, step);
+				if !recipe.steps().any(|s| portions(step, s)) {
+					return Err(invalid(&key, &format!("a {} has no {}", recipe.name(), step.replace('_', " "))));
+				}
+				recipe = recipe.with_portion(|s| portions(step, s), portion(v, &key)?);
+			}
+		}
+		menu = menu.with(recipe);
+	}
+	Ok(menu)
+}
+
+// Finds a milk or syrup by its name, with or without the "Milk" or "Syrup",
+// e.g. "oat" or "Oat Milk".
+fn kind_of<T: Copy + fmt::Display>(all: &[T], v: &Value, key: &str) -> Result<T, ConfigError> {
+	let name = text(v, key)?.trim();
+	all.iter()
+		.find(|k| {
+			let full = k.to_string();
+			full.eq_ignore_ascii_case(name) || full.split_whitespace().next().is_some_and(|w| w.eq_ignore_ascii_case(name))
+		})
+		.copied()
+		.ok_or_else(|| {
+			let kinds: Vec<String> = all.iter().map(|k| k.to_string()).collect();
+			invalid(key, &format!("unknown kind \"{}\", expected one of {}", name, kinds.join(", ")))
+		})
+}
+
+fn components(value: &Value) -> Result<Vec<ComponentConfig>, ConfigError> {
+	let array = value.as_array()
+		.ok_or_else(|| invalid("components", &format!("expected an array of tables, found {}", value.type_str())))?;
+	let mut components: Vec<ComponentConfig> = Vec::new();
+	for (i, v) in array.iter().enumerate() {
+		let parent = format!("components[{}]", i);
+		let c = component(table(v, &parent)?, &parent)?;
+		if components.iter().any(|other| other.name() == c.name()) {
+			return Err(invalid(&parent, &format!("the machine already has a {}", c.name())));
+		}
+		components.push(c);
+	}
+	Ok(components)
+}
+
+fn component(t: &Table, parent: &str) -> Result<ComponentConfig, ConfigError> {
+	const KINDS: [&str; 6] = ["CoffeeHopper", "WaterTank", "EspressoPress", "Frother", "MilkTank", "SyrupPump"];
+	let kind_key = path(parent, "kind");
+	let kind = text(t.get("kind").ok_or_else(|| invalid(&kind_key, "missing, every component needs a kind"))?, &kind_key)?;
+	let kind = KINDS.iter().find(|k| k.eq_ignore_ascii_case(kind.trim()))
+		.ok_or_else(|| invalid(&kind_key, &format!("unknown component \"{}\", expected one of {}", kind, list(KINDS.iter().copied()))))?;
+	// the capacity and starting level of a component that holds material. It
+	// starts full unless given a level.
+	let reservoir = |default: f32| -> Result<(f32, f32), ConfigError> {
+		let capacity = t.get("capacity").map(|v| positive(v, &path(parent, "capacity"))).transpose()?.unwrap_or(default);
+		let level_key = path(parent, "level");
+		let level = t.get("level").map(|v| number(v, &level_key)).transpose()?.unwrap_or(capacity);
+		if level < 0.0 {
+			return Err(invalid(&level_key, &format!("cannot start with less than nothing, found {}", level)));
+		}
+		if level > capacity {
+			return Err(invalid(&level_key, &format!("{} oz. is more than the capacity of {} oz.", level, capacity)));
+		}
+		Ok((capacity, level))
+	};
+	let required = |key: &str| t.get(key).ok_or_else(|| invalid(&path(parent, key), &format!("missing, a {} needs a {}", kind, key)));
+	match *kind {
+		"CoffeeHopper" => {
+			known(t, parent, &["kind", "capacity", "level"])?;
+			let (capacity, level) = reservoir(CAPACITY)?;
+			Ok(ComponentConfig::CoffeeHopper { capacity, level })
+		}
+		"WaterTank" => {
+			known(t, parent, &["kind", "capacity", "level"])?;
+			let (capacity, level) = reservoir(CAPACITY)?;
+			Ok(ComponentConfig::WaterTank { capacity, level })
+		}
+		"EspressoPress" => {
+			known(t, parent, &["kind"])?;
+			Ok(ComponentConfig::EspressoPress)
+		}
+		"Frother" => {
+			known(t, parent, &["kind"])?;
+			Ok(ComponentConfig::Frother)
+		}
+		"MilkTank" => {
+			known(t, parent, &["kind", "milk", "capacity", "level"])?;
+			let milk = kind_of(&Milk::ALL, required("milk")?, &path(parent, "milk"))?;
+			let (capacity, level) = reservoir(CAPACITY)?;
+			Ok(ComponentConfig::MilkTank { milk, capacity, level })
+		}
+		_ => {
+			known(t, parent, &["kind", "syrup", "capacity", "level"])?;
+			let syrup = kind_of(&Syrup::ALL, required("syrup")?, &path(parent, "syrup"))?;
+			let (capacity, level) = reservoir(SYRUP_CAPACITY)?;
+			Ok(ComponentConfig::SyrupPump { syrup, capacity, level })
+		}
+	}
+}
+
+// The timeouts of the stages with components, i.e. every stage but
+// assemble_cups, and of the machine's components by name.
+fn timeouts(value: &Value, components: &[ComponentConfig]) -> Result<Timeouts, ConfigError> {
+	let t = table(value, "timeouts")?;
+	known(t, "timeouts", &["default", "stages", "components"])?;
+	let mut timeouts = match t.get("default") {
+		Some(v) => Timeouts::new(millis(v, "timeouts.default")?),
+		None => Timeouts::default(),
+	};
+	if let Some(v) = t.get("stages") {
+		let stages: Vec<&str> = Stage::ALL.iter().filter(|s| **s != Stage::AssembleCups).map(|s| s.name()).collect();
+		for (name, v) in table(v, "timeouts.stages")? {
+			let key = format!("timeouts.stages.{}", name);
+			if !stages.contains(&name.as_str()) {
+				return Err(invalid(&key, &format!("not a stage with components, expected one of {}", list(stages.iter().copied()))));
+			}
+			timeouts = timeouts.with_stage(name, millis(v, &key)?);
+		}
+	}
+	if let Some(v) = t.get("components") {
+		let names: Vec<&str> = components.iter().map(|c| c.name()).collect();
+		for (name, v) in table(v, "timeouts.components")? {
+			let key = format!("timeouts.components.{}", name);
+			if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+				return Err(invalid(&key, &format!("the machine has no such component, expected one of {}", list(names.iter().copied()))));
+			}
+			timeouts = timeouts.with_component(name, millis(v, &key)?);
+		}
+	}
+	Ok(timeouts)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The key a definition is refused on.
+	fn refused_key(text: &str) -> String {
+		match MachineConfig::from_toml(text) {
+			Err(ConfigError::Invalid { key, .. }) => key,
+			Err(e) => panic!("refused for another reason: {}", e),
+			Ok(_) => panic!("not refused"),
+		}
+	}
+
+	#[test]
+	fn sections_left_out_are_the_standard_machines() {
+		assert_eq!(MachineConfig::from_toml("").unwrap(), MachineConfig::standard());
+		assert_eq!(MachineConfig::from_toml(include_str!("../machine.toml")).unwrap(), MachineConfig::standard());
+	}
+
+	#[test]
+	fn errors_name_the_key_from_the_top_of_the_file() {
+		let components = "[[components]]\nkind = \"CoffeeHopper\"\n\n[[components]]\nkind = \"EspressoPress\"\n\n";
+		assert_eq!(refused_key(&format!("{}[[components]]\nkind = \"WaterTank\"\ncapacity = 10\nlevel = 12", components)), "components[2].level");
+		assert_eq!(refused_key(&format!("{}[[components]]\nkind = \"WaterTank\"\nlevel = -1", components)), "components[2].level");
+		assert_eq!(refused_key(&format!("{}[[components]]\nkind = \"MilkTank\"\nmilk = \"Goat\"", components)), "components[2].milk");
+		assert_eq!(refused_key(&format!("{}[[components]]\nkind = \"MilkTank\"", components)), "components[2].milk");
+		assert_eq!(refused_key("[[components]]\nkind = \"Grinder\""), "components[0].kind");
+		assert_eq!(refused_key("[[components]]\nkind = \"Frother\"\n\n[[components]]\nkind = \"Frother\""), "components[1]");
+		assert_eq!(refused_key("[[components]]\nkind = \"Frother\"\ncapacity = 10"), "components[0].capacity");
+		assert_eq!(refused_key("[timeout]\ndefault = 100"), "timeout");
+		assert_eq!(refused_key("[timeouts]\ndefault = -5"), "timeouts.default");
+		assert_eq!(refused_key("[timeouts.stages]\nassemble_cups = 50"), "timeouts.stages.assemble_cups");
+		assert_eq!(refused_key("[timeouts.components]\nGrinder = 50"), "timeouts.components.Grinder");
+		assert_eq!(refused_key("[dose]\nbeans_per_shot = 0"), "dose.beans_per_shot");
+		assert_eq!(refused_key("[sizes]\nSmall = 8\nHuge = inf"), "sizes.Huge");
+		assert_eq!(refused_key("[stages]\nroast_beans = 100"), "stages.roast_beans");
+		assert_eq!(refused_key("[temperatures]\nsteam = -500"), "temperatures.steam");
+	}
+
+	#[test]
+	fn errors_read_as_the_key_and_the_problem() {
+		let e = MachineConfig::from_toml("[[components]]\nkind = \"WaterTank\"\ncapacity = 10\nlevel = 12").unwrap_err();
+		assert_eq!(e.to_string(), "components[0].level: 12 oz. is more than the capacity of 10 oz.");
+		assert!(matches!(MachineConfig::from_toml("[timeouts"), Err(ConfigError::Syntax(_))));
+	}
+
+	#[test]
+	fn menu_portions_are_set_per_size_or_for_every_size() {
+		let config = MachineConfig::from_toml("[menu.latte]\nmilk = [5, 8, 10]\nfoam = 2\n\n[menu.Flat-White]\nmilk = 3").unwrap();
+		let latte = config.menu().get("Oat Latte").unwrap();
+		assert_eq!(latte.milk(Size::SMALL), 5.0 + 2.0);
+		assert_eq!(latte.milk(Size::LARGE), 10.0 + 2.0);
+		assert_eq!(latte.foam(Size::MEDIUM), 2.0);
+		assert_eq!(config.menu().get("Flat White").unwrap().milk(Size::LARGE), 3.0 + 0.5);
+		assert_eq!(config.menu().get("Cappuccino"), MachineConfig::standard().menu().get("Cappuccino"));
+	}
+
+	#[test]
+	fn menu_portions_need_a_drink_with_that_step() {
+		assert_eq!(refused_key("[menu.Frappuccino]\nmilk = 8"), "menu.Frappuccino");
+		assert_eq!(refused_key("[menu.Americano]\nmilk = 8"), "menu.Americano.milk");
+		assert_eq!(refused_key("[menu.Latte]\nsyrup = 1"), "menu.Latte.syrup");
+		assert_eq!(refused_key("[menu.Latte]\nmilk = [6, 9]"), "menu.Latte.milk");
+		assert_eq!(refused_key("[menu.Latte]\nmilk = [6, 0, 12]"), "menu.Latte.milk[1]");
+	}
+
+	#[test]
+	fn units_and_temperatures_can_be_metric() {
+		let config = MachineConfig::from_toml("units = \"Metric\"\n\n[temperatures]\nbrew = \"85 °C\"\nsteam = \"65C\"\nroom = \"70 °F\"\nfridge = 42").unwrap();
+		assert_eq!(config.units, UnitSystem::Metric);
+		assert_eq!(config.temperatures.brew, Temperature::celsius(85.0));
+		assert_eq!(config.temperatures.steam, Temperature::celsius(65.0));
+		assert_eq!(config.temperatures.room, Temperature::fahrenheit(70.0));
+		assert_eq!(config.temperatures.fridge, Temperature::fahrenheit(42.0));
+		assert_eq!(refused_key("units = \"cubits\""), "units");
+		assert_eq!(refused_key("[temperatures]\nbrew = \"85 K\""), "temperatures.brew");
+		assert_eq!(refused_key("[temperatures]\nbrew = \"hot\""), "temperatures.brew");
+		assert_eq!(refused_key("[temperatures]\nfridge = \"-300 °C\""), "temperatures.fridge");
+	}
+
+	#[test]
+	fn numbers_must_be_finite() {
+		assert_eq!(refused_key("[[components]]\nkind = \"WaterTank\"\nlevel = nan"), "components[0].level");
+		assert_eq!(refused_key("[[components]]\nkind = \"WaterTank\"\ncapacity = inf"), "components[0].capacity");
+		assert_eq!(refused_key("[[components]]\nkind = \"WaterTank\"\ncapacity = 1e300"), "components[0].capacity");
+		assert_eq!(refused_key("[dose]\nwater_per_shot = -inf"), "dose.water_per_shot");
+		assert_eq!(refused_key("[temperatures]\nbrew = nan"), "temperatures.brew");
+		assert_eq!(refused_key("[temperatures]\nbrew = \"inf °C\""), "temperatures.brew");
+		assert_eq!(refused_key("[menu.Latte]\nmilk = [6, nan, 12]"), "menu.Latte.milk[1]");
+	}
+}
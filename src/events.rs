@@ -0,0 +1,109 @@
+use std::fmt;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+use crate::message_based::OrderError;
+
+/// What happened to an order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+	/// The order passed its checks and the machine started making it.
+	Accepted,
+	/// A stage of the machine started on the order.
+	StageStarted(&'static str),
+	/// A stage of the machine finished its part of the order.
+	StageFinished(&'static str),
+	/// A stage of the machine failed, and with it the order. This is the last
+	/// event of an order that fails.
+	StageFailed(OrderError),
+	/// The order's cup is finished. This is the last event of an order that
+	/// is made.
+	Ready,
+}
+impl EventKind {
+	/// The name of the kind of event, e.g. "stage_started".
+	pub fn name(&self) -> &'static str {
+		match self {
+			EventKind::Accepted => "accepted",
+			EventKind::StageStarted(_) => "stage_started",
+			EventKind::StageFinished(_) => "stage_finished",
+			EventKind::StageFailed(_) => "stage_failed",
+			EventKind::Ready => "ready",
+		}
+	}
+
+	/// The stage the event happened at, if it happened at one.
+	pub fn stage(&self) -> Option<&'static str> {
+		match self {
+			EventKind::StageStarted(stage) | EventKind::StageFinished(stage) => Some(stage),
+			EventKind::StageFailed(e) => Some(e.stage),
+			EventKind::Accepted | EventKind::Ready => None,
+		}
+	}
+}
+
+/// Something that happened to an order on a Machine: the order's cup id, who
+/// it is for and what they ordered, and when it happened on the clock of the
+/// machine's Simulation.<br>
+/// Displays as a line for a pickup screen, e.g. "Sharon's Latte: frothing
+/// milk".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+	pub cup_id: usize,
+	pub customer: String,
+	pub drink: String,
+	pub kind: EventKind,
+	pub at: Duration,
+}
+impl fmt::Display for Event {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}'s {}: ", self.customer, self.drink)?;
+		match &self.kind {
+			EventKind::Accepted => write!(f, "order taken"),
+			EventKind::StageStarted(stage) => write!(f, "{}", describe(stage).0),
+			EventKind::StageFinished(stage) => write!(f, "{}", describe(stage).1),
+			EventKind::StageFailed(e) => write!(f, "{}", e),
+			EventKind::Ready => write!(f, "ready"),
+		}
+	}
+}
+
+// What a stage is doing while it works on an order, and what it has done
+// once it is finished.
+fn describe(stage: &'static str) -> (&'static str, &'static str) {
+	match stage {
+		"grind_coffee" => ("grinding coffee", "coffee ground"),
+		"dispense_water" => ("dispensing water", "water dispensed"),
+		"press_espresso" => ("pressing espresso", "espresso pressed"),
+		"heat_milk" => ("heating milk", "milk heated"),
+		"froth_milk" => ("frothing milk", "milk frothed"),
+		"pour_water" => ("pouring hot water", "hot water poured"),
+		"pump_syrup" => ("pumping syrup", "syrup pumped"),
+		"assemble_cups" => ("assembling", "assembled"),
+		_ => (stage, stage),
+	}
+}
+
+/// Hands every Event published on a Machine to each of its subscribers, in
+/// the order they happened. A subscriber that is dropped is forgotten the
+/// next time an event is published.
+#[derive(Default)]
+pub struct EventBus {
+	subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+}
+impl EventBus {
+	pub fn new() -> Self {
+		EventBus::default()
+	}
+
+	/// Subscribes to every event published from now on. The receiver never
+	/// blocks the machine, so events pile up in it until they are received.
+	pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+		let (send, recv) = mpsc::channel();
+		self.subscribers.lock().unwrap().push(send);
+		recv
+	}
+
+	pub fn publish(&self, event: Event) {
+		self.subscribers.lock().unwrap().retain(|s| s.send(event.clone()).is_ok());
+	}
+}
@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::events::{Event, EventKind};
+use crate::logging;
+use crate::machine_components::SizeTable;
+use crate::message_based::{Cup, Machine, OrderStatus};
+use crate::recipe::Menu;
+use crate::units::Amount;
+
+// The most a request body can be, so that a client can't run the machine out
+// of memory.
+const MAX_BODY: usize = 64 * 1024;
+
+/// An HTTP request, as read from a client or made up to call an Api without
+/// a network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+	pub method: String,
+	pub path: String,
+	pub headers: HashMap<String, String>,
+	pub body: String,
+}
+impl Request {
+	pub fn new(method: &str, path: &str, body: &str) -> Self {
+		Request { method: method.to_string(), path: path.to_string(), headers: HashMap::new(), body: body.to_string() }
+	}
+
+	/// Reads a request from a client. Header names are kept in lower case.
+	pub fn read(reader: &mut impl BufRead) -> io::Result<Self> {
+		let bad = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+		let mut line = String::new();
+		reader.read_line(&mut line)?;
+		let mut parts = line.split_whitespace();
+		let (method, path) = match (parts.next(), parts.next()) {
+			(Some(method), Some(path)) => (method.to_string(), path.to_string()),
+			_ => return Err(bad("not an HTTP request")),
+		};
+		let mut headers = HashMap::new();
+		loop {
+			line.clear();
+			if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+				break;
+			}
+			let (name, value) = line.split_once(':').ok_or_else(|| bad("malformed header"))?;
+			headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+		}
+		let length: usize = match headers.get("content-length") {
+			Some(length) => length.parse().map_err(|_| bad("malformed Content-Length"))?,
+			None => 0,
+		};
+		if length > MAX_BODY {
+			return Err(bad("request body too large"));
+		}
+		let mut body = vec![0; length];
+		reader.read_exact(&mut body)?;
+		let body = String::from_utf8(body).map_err(|_| bad("request body is not UTF-8"))?;
+		Ok(Request { method, path, headers, body })
+	}
+}
+
+/// An HTTP response with a JSON body, or a text body held as a JSON string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+	pub status: u16,
+	pub content_type: &'static str,
+	pub body: Value,
+}
+impl Response {
+	pub fn new(status: u16, body: Value) -> Self {
+		Response { status, content_type: "application/json", body }
+	}
+	pub fn text(status: u16, content_type: &'static str, text: String) -> Self {
+		Response { status, content_type, body: Value::String(text) }
+	}
+	fn error(status: u16, message: impl fmt::Display) -> Self {
+		Response::new(status, json!({ "error": message.to_string() }))
+	}
+
+	pub fn reason(&self) -> &'static str {
+		match self.status {
+			200 => "OK",
+			201 => "Created",
+			400 => "Bad Request",
+			404 => "Not Found",
+			405 => "Method Not Allowed",
+			422 => "Unprocessable Entity",
+			503 => "Service Unavailable",
+			_ => "",
+		}
+	}
+
+	/// Writes the response to a client, closing the connection after it.
+	pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+		let body = match &self.body {
+			Value::String(text) if self.content_type != "application/json" => text.clone(),
+			body => body.to_string(),
+		};
+		write!(
+			writer,
+			"HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+			self.status, self.reason(), self.content_type, body.len(), body
+		)?;
+		writer.flush()
+	}
+}
+
+/// The ordering API of a machine, for a till or kiosk to talk to instead of
+/// linking the crate. Every request but `/metrics` is answered with JSON:<br>
+/// `GET /menu` lists the drinks and sizes on offer.<br>
+/// `POST /orders` with `{"customer": "Josh", "drink": "oat latte", "size":
+/// "Medium"}` submits an order, as `Machine::submit` does, and answers with
+/// the order.<br>
+/// `GET /orders` lists every order and `GET /orders/{id}` gives one order:
+/// its status, why it failed if it did, its contents once ready, and when it
+/// started and finished in milliseconds.<br>
+/// `GET /inventory` gives how much each container holds, and
+/// `POST /inventory/{name}/refill` fills a container to its capacity.<br>
+/// `GET /metrics` gives the machine's metrics in the Prometheus text format,
+/// for Prometheus to scrape.<br>
+/// `GET /events` streams every order's events from then on as Server-Sent
+/// Events, for a pickup screen to follow. Only a served Api can stream.<br>
+/// Requests can be handled without a network with `handle`, or served on
+/// localhost with `serve`.
+pub struct Api {
+	machine: Arc<Machine>,
+	menu: Menu,
+	sizes: SizeTable,
+}
+impl Api {
+	pub fn new(machine: Arc<Machine>, menu: Menu, sizes: SizeTable) -> Self {
+		Api { machine, menu, sizes }
+	}
+
+	pub fn machine(&self) -> &Arc<Machine> {
+		&self.machine
+	}
+
+	pub fn handle(&self, request: &Request) -> Response {
+		let path = request.path.split('?').next().unwrap_or_default();
+		let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+		match (request.method.as_str(), segments.as_slice()) {
+			("GET", ["menu"]) => self.menu(),
+			("GET", ["orders"]) => self.orders(),
+			("POST", ["orders"]) => self.submit(&request.body),
+			("GET", ["orders", id]) => match id.parse() {
+				Ok(id) => self.order(id, 200),
+				Err(_) => Response::error(404, format!("{} is not an order number", id)),
+			},
+			("GET", ["inventory"]) => self.inventory(),
+			("POST", ["inventory", name, "refill"]) => self.refill(name),
+			("GET", ["metrics"]) => Response::text(200, "text/plain; version=0.0.4", self.machine.metrics()),
+			("GET", ["events"]) => Response::error(400, "Events can only be streamed from a served Api"),
+			(_, ["menu"] | ["orders"] | ["orders", _] | ["inventory"] | ["inventory", _, "refill"] | ["metrics"] | ["events"]) =>
+				Response::error(405, format!("{} is not allowed on {}", request.method, path)),
+			_ => Response::error(404, format!("Nothing at {}", path)),
+		}
+	}
+
+	fn menu(&self) -> Response {
+		let drinks: Vec<&str> = self.menu.names().collect();
+		let sizes: Vec<Value> = self.sizes.iter()
+			.map(|(name, size)| json!({ "name": name, "ounces": size.ounces() }))
+			.collect();
+		Response::new(200, json!({ "drinks": drinks, "sizes": sizes }))
+	}
+
+	fn submit(&self, body: &str) -> Response {
+		let order: Value = match serde_json::from_str(body) {
+			Ok(order) => order,
+			Err(e) => return Response::error(400, format!("The order is not JSON: {}", e)),
+		};
+		let field = |name: &str| order.get(name).and_then(Value::as_str).map(str::trim).filter(|s| !s.is_empty());
+		let (customer, drink, size) = match (field("customer"), field("drink"), field("size")) {
+			(Some(customer), Some(drink), Some(size)) => (customer, drink, size),
+			_ => return Response::error(400, "An order needs a customer, drink and size"),
+		};
+		let size = match self.sizes.get(size) {
+			Some(size) => size,
+			None => return Response::error(422, format!("{} is not a size on offer", size)),
+		};
+		let cup = match Cup::order(&self.menu, drink, size, customer.to_string()) {
+			Some(cup) => cup,
+			None => return Response::error(422, format!("{} is not on the menu", drink)),
+		};
+		match self.machine.submit(cup) {
+			Ok(handle) => self.order(handle.id(), 201),
+			Err(e) => Response::error(503, format!("Cannot make {}'s Coffee! {}", customer, e)),
+		}
+	}
+
+	fn orders(&self) -> Response {
+		let orders: Vec<Value> = self.machine.orders().iter()
+			.map(|(id, cup, status)| self.order_json(*id, cup, status))
+			.collect();
+		Response::new(200, Value::Array(orders))
+	}
+
+	fn order(&self, id: usize, status: u16) -> Response {
+		match (self.machine.cup(id), self.machine.status(id)) {
+			(Some(cup), Some(order_status)) => Response::new(status, self.order_json(id, &cup, &order_status)),
+			_ => Response::error(404, format!("No order #{}", id)),
+		}
+	}
+
+	fn order_json(&self, id: usize, cup: &Cup, status: &OrderStatus) -> Value {
+		let timing = self.machine.timing(id);
+		let size = self.sizes.name_of(cup.size()).map_or_else(|| cup.size().to_string(), |s| s.to_string());
+		let (state, reason) = match status {
+			OrderStatus::Making { .. } => ("making", None),
+			OrderStatus::Assembling => ("assembling", None),
+			OrderStatus::Ready => ("ready", None),
+			OrderStatus::Failed(e) => ("failed", Some(e.to_string())),
+		};
+		let contents: Vec<String> = cup.contents().iter().map(|i| i.to_string()).collect();
+		json!({
+			"id": id,
+			"customer": cup.client(),
+			"drink": cup.recipe().name(),
+			"size": size,
+			"status": state,
+			"progress": status.to_string(),
+			"reason": reason,
+			"contents": contents,
+			"started_ms": timing.map(|t| t.started.as_millis() as u64),
+			"finished_ms": timing.and_then(|t| t.finished).map(|f| f.as_millis() as u64),
+		})
+	}
+
+	fn inventory(&self) -> Response {
+		let levels: Vec<Value> = self.machine.inventory().into_iter()
+			.map(|(name, level)| json!({ "name": name, "level": level.to_string(), "ounces": ounces(level) }))
+			.collect();
+		Response::new(200, Value::Array(levels))
+	}
+
+	fn refill(&self, name: &str) -> Response {
+		match self.machine.refill(name) {
+			Some(added) => Response::new(200, json!({ "name": name, "added": added.to_string(), "ounces": ounces(added) })),
+			None => Response::error(404, format!("The machine has no container called {}", name)),
+		}
+	}
+}
+
+fn event_json(event: &Event) -> Value {
+	let error = match &event.kind {
+		EventKind::StageFailed(e) => Some(e.error.to_string()),
+		_ => None,
+	};
+	json!({
+		"id": event.cup_id,
+		"customer": event.customer,
+		"drink": event.drink,
+		"event": event.kind.name(),
+		"stage": event.kind.stage(),
+		"error": error,
+		"message": event.to_string(),
+		"at_ms": event.at.as_millis() as u64,
+	})
+}
+
+fn ounces(amount: Amount) -> f32 {
+	match amount {
+		Amount::Mass(m) => m.as_ounces(),
+		Amount::Volume(v) => v.as_fluid_ounces(),
+	}
+}
+
+/// An Api being served on localhost, until it is stopped.
+pub struct Server {
+	addr: SocketAddr,
+	stopping: Arc<AtomicBool>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+impl Server {
+	/// The address the Api is served on, e.g. to find the port when served on
+	/// port 0.
+	pub fn local_addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Blocks for as long as the server runs.
+	pub fn wait(mut self) {
+		if let Some(t) = self.thread.take() {
+			t.join().ok();
+		}
+	}
+
+	/// Stops taking requests and waits for the requests being answered.
+	pub fn stop(mut self) {
+		self.stopping.store(true, Ordering::SeqCst);
+		// wake the listener, which only notices it is stopping on a connection.
+		TcpStream::connect(self.addr).ok();
+		if let Some(t) = self.thread.take() {
+			t.join().ok();
+		}
+	}
+}
+
+/// Serves the Api on a port of localhost, or on any free port for port 0.
+/// Every connection is answered on a thread of its own and then closed, or
+/// for `GET /events`, kept open until the client leaves or the server stops.
+pub fn serve(api: Arc<Api>, port: u16) -> io::Result<Server> {
+	let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+	let addr = listener.local_addr()?;
+	let stopping = Arc::new(AtomicBool::new(false));
+	let stop = Arc::clone(&stopping);
+	let thread = thread::spawn(move || {
+		let mut connections = Vec::new();
+		for stream in listener.incoming() {
+			if stop.load(Ordering::SeqCst) {
+				break;
+			}
+			match stream {
+				Ok(stream) => {
+					let (api, stop) = (Arc::clone(&api), Arc::clone(&stop));
+					connections.push(thread::spawn(move || answer(&api, stream, &stop)));
+				}
+				Err(e) => logging::warn(format!("Error accepting a connection: {}", e)).log(),
+			}
+			connections.retain(|c: &thread::JoinHandle<()>| !c.is_finished());
+		}
+		for c in connections {
+			c.join().ok();
+		}
+	});
+	Ok(Server { addr, stopping, thread: Some(thread) })
+}
+
+// Reads a request from the connection and writes back the Api's answer.
+fn answer(api: &Api, mut stream: TcpStream, stopping: &AtomicBool) {
+	stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+	let request = match stream.try_clone() {
+		Ok(s) => Request::read(&mut BufReader::new(s)),
+		Err(e) => Err(e),
+	};
+	let response = match request {
+		Ok(request) if request.method == "GET" && request.path.split('?').next() == Some("/events") => {
+			if let Err(e) = stream_events(api, &mut stream, stopping) {
+				logging::debug(format!("Stopped streaming events: {}", e)).log();
+			}
+			return;
+		}
+		Ok(request) => api.handle(&request),
+		Err(e) => Response::error(400, e),
+	};
+	if let Err(e) = response.write_to(&mut stream) {
+		logging::warn(format!("Error answering a request: {}", e)).with("status", response.status as usize).log();
+	}
+}
+
+// Writes each event of the Api's machine to the connection as it happens,
+// until the client leaves or the server stops. A comment is written when
+// nothing has happened for a while, to find out if the client has left.
+fn stream_events(api: &Api, stream: &mut TcpStream, stopping: &AtomicBool) -> io::Result<()> {
+	let events = api.machine.subscribe();
+	write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")?;
+	stream.flush()?;
+	let mut idle = 0;
+	while !stopping.load(Ordering::SeqCst) {
+		match events.recv_timeout(Duration::from_secs(1)) {
+			Ok(event) => {
+				idle = 0;
+				write!(stream, "event: {}\ndata: {}\n\n", event.kind.name(), event_json(&event))?;
+			}
+			Err(mpsc::RecvTimeoutError::Timeout) => {
+				idle += 1;
+				if idle % 15 == 0 {
+					write!(stream, ": still here\n\n")?;
+				}
+			}
+			Err(mpsc::RecvTimeoutError::Disconnected) => break,
+		}
+		stream.flush()?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::message_based::Settings;
+	use crate::machine_components::Timeouts;
+	use crate::simulation::Simulation;
+
+	// An Api on the standard machine. Its simulation is seeded, so that an
+	// order is made by the time it is answered, and its timeouts are long
+	// enough that no order fails.
+	fn api() -> Api {
+		logging::Logger::new().install();
+		let settings = Settings { timeouts: Timeouts::new(60_000), simulation: Simulation::seeded(1), ..Settings::default() };
+		let machine = Machine::start_with(Machine::standard_components(), settings);
+		Api::new(Arc::new(machine), Menu::standard(), SizeTable::standard())
+	}
+
+	fn get(api: &Api, path: &str) -> Response {
+		api.handle(&Request::new("GET", path, ""))
+	}
+
+	fn post(api: &Api, path: &str, body: &str) -> Response {
+		api.handle(&Request::new("POST", path, body))
+	}
+
+	#[test]
+	fn lists_the_menu() {
+		let response = get(&api(), "/menu");
+		assert_eq!((response.status, response.content_type), (200, "application/json"));
+		assert!(response.body["drinks"].as_array().unwrap().contains(&json!("Latte")));
+		assert!(response.body["sizes"].as_array().unwrap().contains(&json!({ "name": "Large", "ounces": 16.0 })));
+	}
+
+	#[test]
+	fn submits_and_looks_up_orders() {
+		let api = api();
+		let response = post(&api, "/orders", r#"{"customer": "Josh", "drink": "oat latte", "size": "Medium"}"#);
+		assert_eq!(response.status, 201);
+		assert_eq!(response.body["id"], json!(0));
+		assert_eq!(response.body["customer"], json!("Josh"));
+		assert_eq!(response.body["status"], json!("ready"));
+		assert_eq!(response.body["contents"], json!(["Espresso", "Oat Milk", "Oat Milk Foam"]));
+		assert_eq!(get(&api, "/orders/0").body, response.body);
+		assert_eq!(get(&api, "/orders").body, json!([response.body]));
+	}
+
+	#[test]
+	fn refuses_orders_it_cannot_make() {
+		let api = api();
+		let error = |response: Response| (response.status, response.body["error"].as_str().unwrap().to_string());
+		assert_eq!(post(&api, "/orders", "Josh, Latte").status, 400);
+		assert_eq!(error(post(&api, "/orders", r#"{"customer": "Josh", "drink": "Latte"}"#)), (400, "An order needs a customer, drink and size".to_string()));
+		assert_eq!(error(post(&api, "/orders", r#"{"customer": "Josh", "drink": "Latte", "size": "Huge"}"#)), (422, "Huge is not a size on offer".to_string()));
+		assert_eq!(error(post(&api, "/orders", r#"{"customer": "Josh", "drink": "Mocha", "size": "Large"}"#)), (422, "Mocha is not on the menu".to_string()));
+		assert_eq!(get(&api, "/orders").body, json!([]));
+	}
+
+	#[test]
+	fn refills_containers() {
+		let api = api();
+		post(&api, "/orders", r#"{"customer": "Josh", "drink": "Latte", "size": "Large"}"#);
+		let response = post(&api, "/inventory/wholemilktank/refill", "");
+		assert_eq!(response.status, 200);
+		assert_eq!(response.body["ounces"], json!(13.0));
+		assert_eq!(post(&api, "/inventory/BeerTap/refill", "").status, 404);
+		let levels = get(&api, "/inventory").body;
+		assert!(levels.as_array().unwrap().contains(&json!({ "name": "WholeMilkTank", "level": "64 fl. oz.", "ounces": 64.0 })));
+	}
+
+	#[test]
+	fn answers_every_path_with_its_status() {
+		let api = api();
+		let metrics = get(&api, "/metrics");
+		assert_eq!((metrics.status, metrics.content_type), (200, "text/plain; version=0.0.4"));
+		assert!(metrics.body.as_str().unwrap().contains("# TYPE espresso_inventory_ounces gauge"));
+		assert_eq!(get(&api, "/events").status, 400);
+		assert_eq!(get(&api, "/orders/latest").status, 404);
+		assert_eq!(get(&api, "/orders/7").status, 404);
+		assert_eq!(get(&api, "/coffee").status, 404);
+		assert_eq!(api.handle(&Request::new("DELETE", "/orders/0", "")).status, 405);
+		assert_eq!(post(&api, "/menu", "").status, 405);
+		assert_eq!(get(&api, "/inventory/WaterTank/refill").status, 405);
+	}
+}
@@ -1,108 +1,140 @@
 use std::{time, thread};
 use std::sync::mpsc;
 use std::fmt;
+use crate::logging;
+use crate::units::{Mass, Temperature, Volume};
 
 type S<T> = mpsc::Sender<T>;
 type R<T> = mpsc::Receiver<T>;
-const ROOM_TEMP: f32 = 70.0;
-const FRIDGE_TEMP: f32 = 42.0;
 
-struct CoffeeBeans { weight: f32 }
+/// The temperatures a latte is made at: the room the machine is in, the
+/// fridge the milk comes from, and the water and milk once heated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Temperatures {
+    pub room: Temperature,
+    pub fridge: Temperature,
+    pub brew: Temperature,
+    pub steam: Temperature,
+}
+
+impl Default for Temperatures {
+    fn default() -> Self {
+        Temperatures::STANDARD
+    }
+}
+
+impl Temperatures {
+    pub const STANDARD: Temperatures = Temperatures {
+        room: Temperature::fahrenheit(70.0),
+        fridge: Temperature::fahrenheit(42.0),
+        brew: Temperature::fahrenheit(185.0),
+        steam: Temperature::fahrenheit(150.0),
+    };
+}
+
+struct CoffeeBeans { weight: Mass }
 impl fmt::Display for CoffeeBeans {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} oz. of Coffee Beans", self.weight)
+        write!(f, "{} of Coffee Beans", self.weight)
     }
 }
 
-struct CoffeeGrounds { weight: f32 }
+struct CoffeeGrounds { weight: Mass }
 impl fmt::Display for CoffeeGrounds {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} oz of Coffee Grounds", self.weight)
+        write!(f, "{} of Coffee Grounds", self.weight)
     }
 }
 
-struct Water { weight: f32, temp: f32 }
+struct Water { volume: Volume, temp: Temperature }
 impl fmt::Display for Water {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} oz of Water, temperature: {}", self.weight, self.temp)
+        write!(f, "{} of Water, temperature: {}", self.volume, self.temp)
     }
 }
 
-struct Milk { weight: f32, temp: f32 }
+struct Milk { volume: Volume, temp: Temperature }
 impl fmt::Display for Milk {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} oz of Milk, temperature: {}", self.weight, self.temp)
+        write!(f, "{} of Milk, temperature: {}", self.volume, self.temp)
     }
 }
 
-struct Espresso { weight: f32, temp: f32 }
+struct Espresso { volume: Volume, temp: Temperature }
 impl fmt::Display for Espresso {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} oz of Espresso, temperature: {}", self.weight, self.temp)
+        write!(f, "{} of Espresso, temperature: {}", self.volume, self.temp)
     }
 }
 
-struct Latte { weight: f32, temp: f32 }
+struct Latte { volume: Volume, temp: Temperature }
 impl fmt::Display for Latte {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} oz of Latte, temperature: {}", self.weight, self.temp)
+        write!(f, "{} of Latte, temperature: {}", self.volume, self.temp)
     }
 }
 
+// Logs a stage that couldn't send what it made on to the next.
+fn send_failed(stage: &'static str, channel: &'static str, e: impl fmt::Display) {
+    logging::error(format!("Error in {}: {}", channel, e))
+        .with("stage", stage)
+        .with("error_kind", "disconnected")
+        .log();
+}
+
 fn grind_beans(cb: CoffeeBeans, cg_send: S<CoffeeGrounds>) {
     thread::sleep(time::Duration::from_millis(500));
     let cg = CoffeeGrounds { weight: cb.weight };
-    println!("Made {}!", cg);
+    logging::info(format!("Made {}!", cg)).with("stage", "grind_beans").with("ounces", cg.weight.as_ounces()).log();
     match cg_send.send(cg) {
-        Result::Err(e) => println!("Error in cg_send: {}", e),
-        _ => println!("Coffee beans ground!"),
+        Result::Err(e) => send_failed("grind_beans", "cg_send", e),
+        _ => logging::info("Coffee beans ground!").with("stage", "grind_beans").log(),
     }
     drop(cg_send);
 }
 
-fn heat_water(mut w: Water, w_send: S<Water>) {
+fn heat_water(mut w: Water, brew: Temperature, w_send: S<Water>) {
     thread::sleep(time::Duration::from_millis(1000));
-    w.temp = 185.0;
-    println!("Heated {}!", w);
+    w.temp = brew;
+    logging::info(format!("Heated {}!", w)).with("stage", "heat_water").with("temperature_f", w.temp.as_fahrenheit()).log();
     match w_send.send(w) {
-        Result::Err(e) => println!("Error in w_send: {}", e),
-        _ => println!("Water heated!"),
+        Result::Err(e) => send_failed("heat_water", "w_send", e),
+        _ => logging::info("Water heated!").with("stage", "heat_water").log(),
     }
     drop(w_send);
 }
 
-fn heat_milk(mut m: Milk, m_send: S<Milk>) {
+fn heat_milk(mut m: Milk, steam: Temperature, m_send: S<Milk>) {
     thread::sleep(time::Duration::from_millis(750));
-    m.temp = 150.0;
-    println!("Heated {}!", m);
+    m.temp = steam;
+    logging::info(format!("Heated {}!", m)).with("stage", "heat_milk").with("temperature_f", m.temp.as_fahrenheit()).log();
     match m_send.send(m) {
-        Result::Err(e) => println!("Error in m_send: {}", e),
-        _ => println!("Milk heated!"),
+        Result::Err(e) => send_failed("heat_milk", "m_send", e),
+        _ => logging::info("Milk heated!").with("stage", "heat_milk").log(),
     }
     drop(m_send);
 }
 
-fn press_espresso(cg_recv: R<CoffeeGrounds>, w_recv: R<Water>, e_send: S<Espresso>) {
+fn press_espresso(cg_recv: R<CoffeeGrounds>, w_recv: R<Water>, room: Temperature, e_send: S<Espresso>) {
     thread::sleep(time::Duration::from_millis(500));
-    let mut e = Espresso { weight: 0.0, temp: 0.0 };
+    let mut e = Espresso { volume: Volume::default(), temp: room };
     let cg = cg_recv.recv();
     let w = w_recv.recv();
     if cg.is_ok() {
-        if w.is_ok() {
-            let w = w.unwrap();
-            e.weight = w.weight;
+        if let Result::Ok(w) = w {
+            e.volume = w.volume;
             e.temp = w.temp;
             match e_send.send(e) {
-                Result::Err(e) => println!("Error in e_send: {}", e),
-                _ => println!("Espresso pressed!"),
+                Result::Err(e) => send_failed("press_espresso", "e_send", e),
+                _ => logging::info("Espresso pressed!").with("stage", "press_espresso").log(),
             }
         }
         else {
-            println!("Failed to get water!");
+            logging::error("Failed to get water!").with("stage", "press_espresso").with("error_kind", "disconnected").log();
         }
     }
     else {
-        println!("Failed to get coffee grounds!");
+        logging::error("Failed to get coffee grounds!").with("stage", "press_espresso").with("error_kind", "disconnected").log();
     }
     drop(e_send);
 }
@@ -111,25 +143,41 @@ fn froth_milk(m_recv: R<Milk>, m_send: S<Milk>) {
     thread::sleep(time::Duration::from_millis(500));
     let m = m_recv.recv().unwrap();
     match m_send.send(m) {
-        Result::Err(e) => println!("Error in m_send: {}", e),
-        _ => println!("Milked frothed!"),
+        Result::Err(e) => send_failed("froth_milk", "m_send", e),
+        _ => logging::info("Milked frothed!").with("stage", "froth_milk").log(),
     }
     drop(m_send);
 }
 
 fn make_latte(m_recv: R<Milk>, e_recv: R<Espresso>) {
-    if let Result::Ok(_) = e_recv.recv() {
-        if let Result::Ok(_) = m_recv.recv() {
+    if let Result::Ok(e) = e_recv.recv() {
+        if let Result::Ok(m) = m_recv.recv() {
             thread::sleep(time::Duration::from_millis(250));
-            println!("Enjoy your latte!");
+            // the latte settles at the temperature of its parts, weighted by
+            // how much of each went in.
+            let volume = e.volume + m.volume;
+            let temp = Temperature::fahrenheit(
+                (e.temp.as_fahrenheit() * e.volume.as_fluid_ounces() + m.temp.as_fahrenheit() * m.volume.as_fluid_ounces())
+                    / volume.as_fluid_ounces()
+            );
+            logging::info(format!("Enjoy your {}!", Latte { volume, temp }))
+                .with("stage", "make_latte")
+                .with("fluid_ounces", volume.as_fluid_ounces())
+                .with("temperature_f", temp.as_fahrenheit())
+                .log();
         }
     }
 }
 
 pub fn ingredient_based_main() {
-    let water = Water { weight: 2.0, temp: ROOM_TEMP };
-    let milk = Milk { weight: 6.0, temp: FRIDGE_TEMP };
-    let coffee_beans = CoffeeBeans { weight: 1.0 };
+    ingredient_based_main_at(Temperatures::STANDARD)
+}
+
+/// Makes a latte like `ingredient_based_main`, at the given temperatures.
+pub fn ingredient_based_main_at(temps: Temperatures) {
+    let water = Water { volume: Volume::fluid_ounces(2.0), temp: temps.room };
+    let milk = Milk { volume: Volume::fluid_ounces(6.0), temp: temps.fridge };
+    let coffee_beans = CoffeeBeans { weight: Mass::ounces(1.0) };
 
     use mpsc::channel;
 
@@ -141,19 +189,19 @@ pub fn ingredient_based_main() {
 
     let threads = vec![
         thread::spawn(move || grind_beans(coffee_beans, cg_send1)),
-        thread::spawn(move || heat_water(water, w_send1)),
-        thread::spawn(move || heat_milk(milk, m_send1)),
-        thread::spawn(move || press_espresso(cg_recv1, w_recv1, e_send1)),
+        thread::spawn(move || heat_water(water, temps.brew, w_send1)),
+        thread::spawn(move || heat_milk(milk, temps.steam, m_send1)),
+        thread::spawn(move || press_espresso(cg_recv1, w_recv1, temps.room, e_send1)),
         thread::spawn(move || froth_milk(m_recv1, m_send2)),
     ];
 
     for t in threads {
         if let Result::Err(e) = t.join() {
-            if let Some(e) = e.downcast_ref::<&'static str>() {
-                println!("Error: {}", e);
-            } else {
-                println!("Unknown Error: {:?}", e);
-            }
+            let message = match e.downcast_ref::<&'static str>() {
+                Some(e) => format!("Error: {}", e),
+                None => format!("Unknown Error: {:?}", e),
+            };
+            logging::error(message).log();
         }
     }
 
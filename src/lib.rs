@@ -1,3 +1,26 @@
-// mod ingredient_based;
-mod message_based;
-mod machine_components;
\ No newline at end of file
+//! A simulated espresso machine.<br>
+//! `message_based::Machine` is the machine itself: start one, submit Cups
+//! ordered from a `recipe::Menu` to it, await the handle of each order for the
+//! finished cup or look up its status, check its inventory and refill it, then
+//! shut it down. The components it is built from are in `machine_components`,
+//! and a store's machine can be described in a file with `config`. A file of
+//! orders can be replayed against a machine with `batch`, and a till or kiosk
+//! can order over HTTP from the API `http` serves on localhost. Everything that
+//! happens to an order is published as an `events::Event`, which can be
+//! subscribed to on the machine or streamed from the API. The machine logs
+//! what it does through `logging`, to the terminal or a JSON lines file, and
+//! counts how it runs in `metrics`, for Prometheus to scrape from the API.
+pub mod batch;
+pub mod builder_based;
+pub mod config;
+pub mod events;
+pub mod http;
+pub mod ingredient_based;
+pub mod logging;
+pub mod message_based;
+pub mod machine_components;
+pub mod metrics;
+pub mod recipe;
+pub mod shift;
+pub mod simulation;
+pub mod units;
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{Map, Number, Value};
+
+/// How much a record matters, from the least to the most.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	Debug,
+	Info,
+	Warn,
+	Error,
+}
+impl Level {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Level::Debug => "debug",
+			Level::Info => "info",
+			Level::Warn => "warn",
+			Level::Error => "error",
+		}
+	}
+}
+impl fmt::Display for Level {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		// padded, so that the messages of a console log line up.
+		write!(f, "{:<5}", self.name().to_uppercase())
+	}
+}
+impl FromStr for Level {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"debug" => Ok(Level::Debug),
+			"info" => Ok(Level::Info),
+			"warn" | "warning" => Ok(Level::Warn),
+			"error" => Ok(Level::Error),
+			_ => Err(format!("{} is not a log level, expected debug, info, warn or error", s)),
+		}
+	}
+}
+
+/// The value of a field of a record: text, or a number that a JSON lines log
+/// keeps as a number.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+	Text(String),
+	Number(f64),
+}
+impl fmt::Display for FieldValue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			FieldValue::Text(t) => write!(f, "{}", t),
+			FieldValue::Number(n) => write!(f, "{}", n),
+		}
+	}
+}
+impl From<&str> for FieldValue {
+	fn from(t: &str) -> Self {
+		FieldValue::Text(t.to_string())
+	}
+}
+impl From<String> for FieldValue {
+	fn from(t: String) -> Self {
+		FieldValue::Text(t)
+	}
+}
+impl From<usize> for FieldValue {
+	fn from(n: usize) -> Self {
+		FieldValue::Number(n as f64)
+	}
+}
+impl From<f32> for FieldValue {
+	fn from(n: f32) -> Self {
+		FieldValue::Number(n as f64)
+	}
+}
+/// A duration is given in milliseconds.
+impl From<Duration> for FieldValue {
+	fn from(d: Duration) -> Self {
+		FieldValue::Number(d.as_secs_f64() * 1000.0)
+	}
+}
+
+/// Something that happened on the machine: a message for a person to read,
+/// along with fields for a program to search on, e.g. the cup id and stage.
+/// Records are made with `debug`, `info`, `warn` or `error`, given fields
+/// with `with`, then handed to the installed Logger with `log`, e.g.
+/// `logging::info("Milk heated").with("cup_id", cup_id).log()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+	pub level: Level,
+	pub message: String,
+	pub fields: Vec<(&'static str, FieldValue)>,
+	pub at: SystemTime,
+}
+impl Record {
+	pub fn new(level: Level, message: impl Into<String>) -> Self {
+		Record { level, message: message.into(), fields: Vec::new(), at: SystemTime::now() }
+	}
+
+	pub fn with(mut self, key: &'static str, value: impl Into<FieldValue>) -> Self {
+		self.fields.push((key, value.into()));
+		self
+	}
+
+	/// Hands the record to the installed Logger.
+	pub fn log(self) {
+		match LOGGER.read().unwrap().as_ref() {
+			Some(logger) => logger.log(&self),
+			None if self.level >= Level::Info => Console.write(&self),
+			None => (),
+		}
+	}
+
+	/// The record as a line of text, e.g.
+	/// `INFO  Milk heated for Client 3! cup_id=3 stage=heat_milk`. A field
+	/// with spaces in it is quoted.
+	pub fn to_text(&self) -> String {
+		let mut line = format!("{} {}", self.level, self.message);
+		for (key, value) in &self.fields {
+			match value {
+				FieldValue::Text(t) if t.contains(char::is_whitespace) => line += &format!(" {}={:?}", key, t),
+				value => line += &format!(" {}={}", key, value),
+			}
+		}
+		line
+	}
+
+	/// The record as a JSON object on one line, with the time in milliseconds
+	/// since the Unix epoch, the level and the message, followed by the
+	/// fields.
+	pub fn to_json(&self) -> String {
+		let mut object = Map::new();
+		let ms = self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+		object.insert("ts_ms".to_string(), Value::from(ms));
+		object.insert("level".to_string(), Value::from(self.level.name()));
+		object.insert("message".to_string(), Value::from(self.message.as_str()));
+		for (key, value) in &self.fields {
+			let value = match value {
+				FieldValue::Text(t) => Value::from(t.as_str()),
+				// whole numbers, e.g. a cup id, are kept whole.
+				FieldValue::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => Value::from(*n as i64),
+				FieldValue::Number(n) => Number::from_f64(*n).map_or(Value::Null, Value::Number),
+			};
+			object.insert(key.to_string(), value);
+		}
+		Value::Object(object).to_string()
+	}
+}
+
+pub fn debug(message: impl Into<String>) -> Record {
+	Record::new(Level::Debug, message)
+}
+pub fn info(message: impl Into<String>) -> Record {
+	Record::new(Level::Info, message)
+}
+pub fn warn(message: impl Into<String>) -> Record {
+	Record::new(Level::Warn, message)
+}
+pub fn error(message: impl Into<String>) -> Record {
+	Record::new(Level::Error, message)
+}
+
+/// Somewhere records are written to.
+pub trait Sink: Send + Sync {
+	fn write(&self, record: &Record);
+}
+
+/// Writes each record to the terminal as a line of text.
+pub struct Console;
+impl Sink for Console {
+	fn write(&self, record: &Record) {
+		// unlike println!, a closed terminal doesn't bring the stage down.
+		writeln!(io::stdout().lock(), "{}", record.to_text()).ok();
+	}
+}
+
+/// Writes each record to a file as a line of JSON, so that a day's log can
+/// be searched by field, e.g. every record with a cup_id of 12.
+pub struct JsonLines {
+	file: Mutex<LineWriter<File>>,
+}
+impl JsonLines {
+	/// Opens a log file, adding to the end of it if it exists already.
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(JsonLines { file: Mutex::new(LineWriter::new(file)) })
+	}
+}
+impl Sink for JsonLines {
+	fn write(&self, record: &Record) {
+		// a log that can't be written to can't report it either.
+		writeln!(self.file.lock().unwrap(), "{}", record.to_json()).ok();
+	}
+}
+
+/// The sinks records are written to, each with the least level it takes.
+/// Until a Logger is installed, records of Info and above are written to the
+/// Console.
+#[derive(Default)]
+pub struct Logger {
+	sinks: Vec<(Level, Box<dyn Sink>)>,
+}
+impl Logger {
+	/// A Logger without any sinks, which drops every record.
+	pub fn new() -> Self {
+		Logger::default()
+	}
+
+	pub fn with_sink(mut self, level: Level, sink: impl Sink + 'static) -> Self {
+		self.sinks.push((level, Box::new(sink)));
+		self
+	}
+
+	pub fn log(&self, record: &Record) {
+		for (level, sink) in &self.sinks {
+			if record.level >= *level {
+				sink.write(record);
+			}
+		}
+	}
+
+	/// Makes this the Logger every record is handed to, in place of the one
+	/// installed before.
+	pub fn install(self) {
+		*LOGGER.write().unwrap() = Some(self);
+	}
+}
+
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
@@ -1,254 +1,1026 @@
 use std::fmt;
-use std::{time, thread};
-use rand::{thread_rng, Rng};
-use std::sync::mpsc;
-
-// The "amount" of beans in coffee hopper in ounces.
-const BEANAMOUNT: f32 = 2.0;
-// The "amount" of water in tank in ounces.
-const WATERAMOUNT: f32 = 2.0;
-// The "amount" of milk in tank in ounces.
-const MILKAMOUNT: f32 = 10.0;
-// The "capacity" of each hopper or tank.
-const CAPACITY: f32 = 64.0;
-type S<T> = mpsc::Sender<T>;
-type R<T> = mpsc::Receiver<T>;
+use std::time;
+use std::sync::{Arc, Mutex};
+use crate::metrics;
+use crate::simulation;
+use crate::units::{Amount, Unit, Volume};
 
+/// The capacity of each hopper or tank in ounces, unless given another.
+pub const CAPACITY: f32 = 64.0;
+/// The capacity of each syrup bottle in ounces, unless given another.
+pub const SYRUP_CAPACITY: f32 = 25.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Ingredient {
 	Espresso,
-	Milk,
+	Milk(Milk),
+	Foam(Milk),
+	HotWater,
+	Syrup(Syrup),
 }
 impl fmt::Display for Ingredient {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		use Ingredient::*;
 		match self {
 			Espresso => write!(f, "Espresso"),
-			Milk => write!(f, "Milk"),
+			Milk(m) => write!(f, "{}", m),
+			Foam(m) => write!(f, "{} Foam", m),
+			HotWater => write!(f, "Hot Water"),
+			Syrup(s) => write!(f, "{}", s),
+		}
+	}
+}
+
+/// The kinds of milk the machine can keep a tank of.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Milk {
+	Whole,
+	Skim,
+	Oat,
+	Almond,
+	Soy,
+}
+impl Milk {
+	pub const ALL: [Milk; 5] = [Milk::Whole, Milk::Skim, Milk::Oat, Milk::Almond, Milk::Soy];
+}
+impl fmt::Display for Milk {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use Milk::*;
+		match self {
+			Whole => write!(f, "Whole Milk"),
+			Skim => write!(f, "Skim Milk"),
+			Oat => write!(f, "Oat Milk"),
+			Almond => write!(f, "Almond Milk"),
+			Soy => write!(f, "Soy Milk"),
+		}
+	}
+}
+
+/// The flavour syrups the machine can keep a bottle of.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Syrup {
+	Vanilla,
+	Caramel,
+	Hazelnut,
+}
+impl Syrup {
+	pub const ALL: [Syrup; 3] = [Syrup::Vanilla, Syrup::Caramel, Syrup::Hazelnut];
+}
+impl fmt::Display for Syrup {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use Syrup::*;
+		match self {
+			Vanilla => write!(f, "Vanilla Syrup"),
+			Caramel => write!(f, "Caramel Syrup"),
+			Hazelnut => write!(f, "Hazelnut Syrup"),
 		}
 	}
 }
 
-#[derive(Copy, Clone)]
-pub enum Size {
-	Small,
-	Medium,
-	Large,
+/// The size of a customer's cup in fluid ounces. The standard small, medium
+/// and large cups are 8, 12 and 16 oz., but any size can be made, e.g. a 20
+/// oz. cup or a 4 oz. kids cup.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Size {
+	ounces: f32,
+}
+impl Size {
+	pub const SMALL: Size = Size::oz(8.0);
+	pub const MEDIUM: Size = Size::oz(12.0);
+	pub const LARGE: Size = Size::oz(16.0);
+
+	pub const fn oz(ounces: f32) -> Self {
+		Size { ounces }
+	}
+	pub fn ounces(&self) -> f32 {
+		self.ounces
+	}
+	pub fn volume(&self) -> Volume {
+		Volume::fluid_ounces(self.ounces)
+	}
 }
 impl fmt::Display for Size {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		use Size::*;
+		write!(f, "{}", self.volume())
+	}
+}
+
+/// The sizes of cup on offer, by name. Adding a size is a matter of adding a
+/// row to the table; the amount of each ingredient that goes into it is
+/// worked out from the recipe (see `Portion`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeTable {
+	sizes: Vec<(String, Size)>,
+}
+impl Default for SizeTable {
+	fn default() -> Self {
+		SizeTable::standard()
+	}
+}
+impl SizeTable {
+	pub fn new() -> Self {
+		SizeTable { sizes: Vec::new() }
+	}
+	/// The standard small, medium and large cups.
+	pub fn standard() -> Self {
+		SizeTable::new()
+			.with("Small", Size::SMALL)
+			.with("Medium", Size::MEDIUM)
+			.with("Large", Size::LARGE)
+	}
+	/// Adds a size to the table, replacing any size with the same name.
+	pub fn with(mut self, name: &str, size: Size) -> Self {
+		self.sizes.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+		self.sizes.push((name.to_string(), size));
+		self
+	}
+	/// Looks up a size by name, ignoring case.
+	pub fn get(&self, name: &str) -> Option<Size> {
+		self.sizes.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(name.trim()))
+			.map(|(_, s)| *s)
+	}
+	/// The name of a size in the table, if it has one.
+	pub fn name_of(&self, size: Size) -> Option<&str> {
+		self.sizes.iter()
+			.find(|(_, s)| *s == size)
+			.map(|(n, _)| n.as_str())
+	}
+	pub fn iter(&self) -> impl Iterator<Item = (&str, Size)> {
+		self.sizes.iter().map(|(n, s)| (n.as_str(), *s))
+	}
+}
+
+/// The ways a machine component can fail to do its job.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MachineError {
+	/// The component took longer than the timeout (in milliseconds) to answer.
+	Timeout { component: &'static str, timeout: usize, elapsed: usize },
+	/// A hopper or tank holds less material than a job needs.
+	InsufficientMaterial { component: &'static str, required: Amount, available: Amount },
+	/// The component answered but could not complete the job.
+	MechanicalFault { component: &'static str, reason: String },
+	/// A job asked a container for a negative amount, or an amount that is
+	/// not a number.
+	InvalidAmount { component: &'static str, amount: Amount },
+	/// A pipeline stage could not reach the channel it sends orders to.
+	Disconnected { stage: &'static str },
+	/// The machine has no component for something an order needs, e.g. a
+	/// latte with oat milk on a machine without an oat milk tank.
+	Unavailable { supply: String },
+}
+impl fmt::Display for MachineError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use MachineError::*;
 		match self {
-			Small => write!(f, "8 oz."),
-			Medium => write!(f, "12 oz."),
-			Large => write!(f, "16 oz."),
+			Timeout { component, timeout, elapsed } => write!(
+				f, "{} Component Not Responding (no answer after {} ms, timeout is {} ms)", component, elapsed, timeout
+			),
+			InsufficientMaterial { component, required, available } => write!(
+				f, "Not enough material in {} ({} required, {} available)", component, required, available
+			),
+			MechanicalFault { component, reason } => write!(f, "{} Component Fault: {}", component, reason),
+			InvalidAmount { component, amount } => write!(f, "Cannot draw {} from {}", amount, component),
+			Disconnected { stage } => write!(f, "{} stage is disconnected from the pipeline", stage),
+			Unavailable { supply } => write!(f, "No component in the machine for {}", supply),
 		}
 	}
 }
+impl std::error::Error for MachineError {}
+impl MachineError {
+	/// Whether trying again might get past the error, i.e. the component was
+	/// slow to answer or had a fault, rather than the machine being short of
+	/// something.
+	pub fn is_transient(&self) -> bool {
+		matches!(self, MachineError::Timeout { .. } | MachineError::MechanicalFault { .. })
+	}
 
-pub trait Ping {
-	fn ping(timeout: usize) -> Result<(), String>;
+	/// The name of the kind of error, e.g. "timeout", for logs and metrics.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			MachineError::Timeout { .. } => "timeout",
+			MachineError::InsufficientMaterial { .. } => "insufficient_material",
+			MachineError::MechanicalFault { .. } => "mechanical_fault",
+			MachineError::InvalidAmount { .. } => "invalid_amount",
+			MachineError::Disconnected { .. } => "disconnected",
+			MachineError::Unavailable { .. } => "unavailable",
+		}
+	}
+
+	/// The component the error came from, if it came from one.
+	pub fn component(&self) -> Option<&'static str> {
+		match self {
+			MachineError::Timeout { component, .. }
+			| MachineError::InsufficientMaterial { component, .. }
+			| MachineError::MechanicalFault { component, .. }
+			| MachineError::InvalidAmount { component, .. } => Some(component),
+			MachineError::Disconnected { .. } | MachineError::Unavailable { .. } => None,
+		}
+	}
 }
-pub trait Capacity {
-	fn check_capacity(s: Size) -> Result<(), String>;
+
+/// How long to wait before trying a component again.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Backoff {
+	/// The same milliseconds before every attempt.
+	Fixed(u64),
+	/// The initial milliseconds before the second attempt, doubled before
+	/// every attempt after that, up to the max.
+	Exponential { initial: u64, max: u64 },
 }
 
-pub trait ExecJob {
-	fn exec_job(timeout: usize, size: Option<Size>) -> Result<(), String>;
+/// How many times a component's ping or job is tried before giving up on
+/// it, and how long to wait between tries. Only transient errors are tried
+/// again.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+	pub max_attempts: usize,
+	pub backoff: Backoff,
+}
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		RetryPolicy::NONE
+	}
 }
+impl RetryPolicy {
+	/// Gives up on the first failure.
+	pub const NONE: RetryPolicy = RetryPolicy { max_attempts: 1, backoff: Backoff::Fixed(0) };
 
-pub struct CoffeeHopper;
-impl Ping for CoffeeHopper {
-	fn ping(timeout: usize) -> Result<(), String> {
-		let rng = thread_rng().gen_range(2..100);
-		thread::sleep(time::Duration::from_millis(rng));
-		if rng as usize > timeout {
-			Err("CoffeeHopper Component Not Responding".to_string())
+	pub fn fixed(max_attempts: usize, millis: u64) -> Self {
+		RetryPolicy { max_attempts, backoff: Backoff::Fixed(millis) }
+	}
+	pub fn exponential(max_attempts: usize, initial: u64, max: u64) -> Self {
+		RetryPolicy { max_attempts, backoff: Backoff::Exponential { initial, max } }
+	}
+	/// How long to wait after the given attempt (counting from 1) failed.
+	pub fn delay(&self, attempt: usize) -> time::Duration {
+		let millis = match self.backoff {
+			Backoff::Fixed(millis) => millis,
+			Backoff::Exponential { initial, max } => {
+				let doublings = attempt.saturating_sub(1).min(63) as u32;
+				initial.saturating_mul(1u64 << doublings).min(max)
+			}
+		};
+		time::Duration::from_millis(millis)
+	}
+	/// Runs the job until it succeeds, fails with an error that isn't
+	/// transient or runs out of attempts. Returns the last result along with
+	/// how many attempts were made.
+	pub fn run<T>(&self, mut job: impl FnMut() -> Result<T, MachineError>) -> (Result<T, MachineError>, usize) {
+		let mut attempt = 1;
+		loop {
+			match job() {
+				Err(e) if e.is_transient() && attempt < self.max_attempts => {
+					simulation::sleep(self.delay(attempt));
+					attempt += 1;
+				}
+				result => return (result, attempt),
+			}
+		}
+	}
+}
+
+/// The RetryPolicy for each component of a machine: a default for every
+/// component, and overrides for components by name, e.g.
+/// `RetryPolicies::new(RetryPolicy::fixed(3, 10)).with("WaterTank", RetryPolicy::NONE)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryPolicies {
+	default: RetryPolicy,
+	overrides: Vec<(String, RetryPolicy)>,
+}
+impl RetryPolicies {
+	pub fn new(default: RetryPolicy) -> Self {
+		RetryPolicies { default, overrides: Vec::new() }
+	}
+	/// Overrides the policy for the component with the given name, replacing
+	/// any override it already has.
+	pub fn with(mut self, component: &str, policy: RetryPolicy) -> Self {
+		self.overrides.retain(|(name, _)| !name.eq_ignore_ascii_case(component));
+		self.overrides.push((component.to_string(), policy));
+		self
+	}
+	/// The policy for the component with the given name.
+	pub fn get(&self, component: &str) -> RetryPolicy {
+		self.overrides.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(component))
+			.map_or(self.default, |(_, policy)| *policy)
+	}
+}
+
+/// How long to wait in milliseconds for the components of a machine to
+/// answer: a default for every component, overridden for the components of a
+/// pipeline stage and for components by name, e.g.
+/// `Timeouts::new(100).with_stage("heat_milk", 150).with_component("WaterTank", 80)`.
+/// A component's own timeout comes before the timeout of its stage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeouts {
+	default: usize,
+	stages: Vec<(String, usize)>,
+	components: Vec<(String, usize)>,
+}
+impl Default for Timeouts {
+	fn default() -> Self {
+		Timeouts::new(100)
+	}
+}
+impl Timeouts {
+	pub fn new(default: usize) -> Self {
+		Timeouts { default, stages: Vec::new(), components: Vec::new() }
+	}
+	/// Overrides the timeout for the components of the stage with the given
+	/// name, e.g. "dispense_water".
+	pub fn with_stage(mut self, stage: &str, timeout: usize) -> Self {
+		self.stages.retain(|(name, _)| !name.eq_ignore_ascii_case(stage));
+		self.stages.push((stage.to_string(), timeout));
+		self
+	}
+	/// Overrides the timeout for the component with the given name, wherever
+	/// it is used.
+	pub fn with_component(mut self, component: &str, timeout: usize) -> Self {
+		self.components.retain(|(name, _)| !name.eq_ignore_ascii_case(component));
+		self.components.push((component.to_string(), timeout));
+		self
+	}
+	pub fn default_timeout(&self) -> usize {
+		self.default
+	}
+	/// The timeout for the component with the given name, in the given stage
+	/// if it is used in one.
+	pub fn get(&self, component: &str, stage: Option<&str>) -> usize {
+		let find = |overrides: &[(String, usize)], name: &str| overrides.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(name))
+			.map(|(_, timeout)| *timeout);
+		find(&self.components, component)
+			.or_else(|| stage.and_then(|stage| find(&self.stages, stage)))
+			.unwrap_or(self.default)
+	}
+}
+
+/// How long a component takes to answer a ping, in milliseconds. It usually
+/// answers somewhere between the fastest and slowest times, but now and then
+/// it stalls for up to the longest stall, which can take it past a timeout.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PingModel {
+	pub fastest: u64,
+	pub slowest: u64,
+	/// The chance of a stall on each ping, from 0 to 1.
+	pub stall_chance: f32,
+	pub longest_stall: u64,
+}
+impl Default for PingModel {
+	fn default() -> Self {
+		PingModel::STANDARD
+	}
+}
+impl PingModel {
+	/// Answers in 2 to 40 ms, stalling on one ping in fifty for up to 250 ms.
+	pub const STANDARD: PingModel = PingModel { fastest: 2, slowest: 40, stall_chance: 0.02, longest_stall: 250 };
+
+	/// How long the component takes to answer a ping, drawn from the
+	/// simulation the thread is running in.
+	pub fn answer_time(&self) -> u64 {
+		let slowest = self.slowest.max(self.fastest);
+		let stalled = (simulation::gen_range(0..1_000_000) as f32) < self.stall_chance * 1_000_000.0;
+		if stalled && self.longest_stall > slowest {
+			simulation::gen_range(slowest..self.longest_stall + 1)
+		} else {
+			simulation::gen_range(self.fastest..slowest + 1)
+		}
+	}
+}
+
+pub trait Ping {
+	/// The name of the component, used when reporting errors.
+	fn name(&self) -> &'static str;
+	/// How long the component takes to answer a ping.
+	fn ping_model(&self) -> PingModel {
+		PingModel::STANDARD
+	}
+	/// Waits for the component to respond, failing if it takes longer than
+	/// the timeout in milliseconds. A component that doesn't answer in time
+	/// is only waited on until the timeout.
+	fn ping(&self, timeout: usize) -> Result<(), MachineError> {
+		let answer = self.ping_model().answer_time() as usize;
+		let waited = time::Duration::from_millis(answer.min(timeout) as u64);
+		metrics::global().ping_took(self.name(), waited);
+		simulation::sleep(waited);
+		if answer > timeout {
+			Err(MachineError::Timeout { component: self.name(), timeout, elapsed: timeout })
 		} else {
 			Ok(())
 		}
 	}
 }
-impl Capacity for CoffeeHopper {
-	fn check_capacity(s: Size) -> Result<(), String> {
-		use Size::*;
-		let s: f32 = match s {
-			Small => 1.0,
-			Medium => 2.0,
-			Large => 3.0,
-		};
-		if s <= BEANAMOUNT {
-			Ok(())	
+pub trait Capacity: Ping {
+	/// What the component measures its material in, e.g. beans by weight.
+	fn unit(&self) -> Unit;
+	/// The ounces of material the component currently holds.
+	fn level(&self) -> f32;
+	/// The most ounces of material the component can hold.
+	fn capacity(&self) -> f32;
+	/// Checks that the component holds at least the given ounces of material.
+	fn check_capacity(&self, required: f32) -> Result<(), MachineError> {
+		let available = self.level();
+		if required <= available {
+			Ok(())
 		} else {
-			Err("Not enough coffee beans in CoffeeHopper".to_string())
+			Err(MachineError::InsufficientMaterial {
+				component: self.name(),
+				required: self.unit().amount(required),
+				available: self.unit().amount(available),
+			})
 		}
 	}
 }
-impl CoffeeHopper {
-	fn grind_beans(timeout: usize, size: Option<Size>) -> Result<(), String> {
-		if let Err(e) = CoffeeHopper::ping(timeout) {
-			return Err(e.to_string());
-		}
-		match size {
-			Some(s) => {
-				if let Err(e) = CoffeeHopper::check_capacity(s) {
-					return Err(e.to_string());
-				}
+
+pub trait ExecJob: Ping {
+	/// Does the component's job for an order. The amount is how much of the
+	/// job the order needs, e.g. the ounces drawn from a tank or the shots
+	/// pulled by the press. Components that hold material skip drawing from
+	/// it when no amount is given.
+	fn exec_job(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError>;
+}
+
+/// Implemented by components that hold material and can be restocked.
+pub trait Refill: Ping {
+	/// Fills the component up to its capacity, returning the ounces added.
+	fn refill(&self) -> f32;
+	/// Adds the given ounces to the component. Fails without adding anything
+	/// if the amount would take the component over its capacity.
+	fn top_up(&self, amount: f32) -> Result<(), RefillError>;
+}
+
+#[derive(Debug)]
+pub enum RefillError {
+	/// The amount added would overflow the component.
+	Overfill { component: &'static str, amount: Amount, space: Amount },
+	/// The amount added was negative or not a number.
+	InvalidAmount { component: &'static str, amount: Amount },
+}
+impl fmt::Display for RefillError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use RefillError::*;
+		match self {
+			Overfill { component, amount, space } => write!(
+				f, "Cannot add {} to {}, only {} of space left", amount, component, space
+			),
+			InvalidAmount { component, amount } => write!(
+				f, "Cannot add {} to {}", amount, component
+			),
+		}
+	}
+}
+impl std::error::Error for RefillError {}
+
+/// A component that holds material, i.e. one that can be checked, drawn from
+/// and restocked.
+pub trait Container: Capacity + ExecJob + Refill {}
+impl<T: Capacity + ExecJob + Refill> Container for T {}
+
+/// A component of the machine behind a trait object, so that a machine can
+/// be put together from a list of components decided at runtime.
+#[derive(Clone)]
+pub enum Component {
+	/// A component that only does work, e.g. an EspressoPress.
+	Device(Arc<dyn ExecJob + Send + Sync>),
+	/// A component that holds material, e.g. a MilkTank.
+	Container(Arc<dyn Container + Send + Sync>),
+}
+impl Component {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Component::Device(d) => d.name(),
+			Component::Container(c) => c.name(),
+		}
+	}
+	/// The component as something a pipeline stage can run jobs on.
+	pub fn job(&self) -> Arc<dyn ExecJob + Send + Sync> {
+		match self {
+			Component::Device(d) => Arc::clone(d),
+			Component::Container(c) => Arc::clone(c) as Arc<dyn ExecJob + Send + Sync>,
+		}
+	}
+}
+
+/// The material held by a hopper or tank, measured in ounces of weight or
+/// volume depending on the material.<br>
+/// The current level sits behind a mutex so that a component shared between
+/// the pipeline threads (e.g. through an Arc) can be checked and drawn from
+/// without two threads dispensing the same ounces.
+pub struct Reservoir {
+	level: Mutex<f32>,
+	capacity: f32,
+}
+impl Reservoir {
+	/// Creates a reservoir holding the given level, kept between empty and
+	/// the capacity. A capacity that is negative or not a finite number holds
+	/// nothing, and a level that is not a number starts empty.
+	pub fn new(level: f32, capacity: f32) -> Self {
+		let capacity = if capacity.is_finite() && capacity > 0.0 { capacity } else { 0.0 };
+		let level = if level.is_nan() { 0.0 } else { level.clamp(0.0, capacity) };
+		Reservoir {
+			level: Mutex::new(level),
+			capacity,
+		}
+	}
+	pub fn level(&self) -> f32 {
+		*self.level.lock().unwrap()
+	}
+	pub fn capacity(&self) -> f32 {
+		self.capacity
+	}
+	/// Sets the reservoir to its capacity, returning the amount added.<br>
+	/// A pipeline stage drawing from the reservoir waits on the lock while
+	/// this happens, so only that stage pauses during a refill.
+	pub fn fill(&self) -> f32 {
+		let mut level = self.level.lock().unwrap();
+		let added = self.capacity - *level;
+		*level = self.capacity;
+		added
+	}
+	/// Adds the given amount to the reservoir. On failure nothing is added,
+	/// and if the amount was too much, the space left is returned.
+	pub fn add(&self, amount: f32) -> Result<(), ReservoirError> {
+		check_amount(amount)?;
+		let mut level = self.level.lock().unwrap();
+		if *level + amount <= self.capacity {
+			*level += amount;
+			Ok(())
+		} else {
+			Err(ReservoirError::NoSpace(self.capacity - *level))
+		}
+	}
+	/// Removes the given amount from the reservoir. The check and the
+	/// subtraction happen under the same lock, so the reservoir can never go
+	/// below zero. On failure nothing is removed, and if the amount was too
+	/// much, the amount still available is returned.
+	pub fn take(&self, amount: f32) -> Result<(), ReservoirError> {
+		check_amount(amount)?;
+		let mut level = self.level.lock().unwrap();
+		if amount <= *level {
+			*level -= amount;
+			Ok(())
+		} else {
+			Err(ReservoirError::NotEnough(*level))
+		}
+	}
+}
+
+// Only an amount of zero or more can be added to or taken from a reservoir,
+// so that neither can take it below zero or over its capacity.
+fn check_amount(amount: f32) -> Result<(), ReservoirError> {
+	if amount.is_nan() || amount < 0.0 {
+		Err(ReservoirError::InvalidAmount)
+	} else {
+		Ok(())
+	}
+}
+
+/// Why a Reservoir couldn't add or take an amount.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReservoirError {
+	/// The amount was negative or not a number.
+	InvalidAmount,
+	/// The amount would overflow the reservoir, which has this much space left.
+	NoSpace(f32),
+	/// The reservoir holds less than the amount, only this much.
+	NotEnough(f32),
+}
+impl ReservoirError {
+	/// The error for a container that couldn't add an amount of material.
+	pub fn refill_error(self, component: &'static str, unit: Unit, amount: f32) -> RefillError {
+		match self {
+			ReservoirError::NoSpace(space) => RefillError::Overfill {
+				component,
+				amount: unit.amount(amount),
+				space: unit.amount(space),
+			},
+			_ => RefillError::InvalidAmount { component, amount: unit.amount(amount) },
+		}
+	}
+	/// The error for a container that couldn't give an amount of material.
+	pub fn machine_error(self, component: &'static str, unit: Unit, amount: f32) -> MachineError {
+		match self {
+			ReservoirError::NotEnough(available) => MachineError::InsufficientMaterial {
+				component,
+				required: unit.amount(amount),
+				available: unit.amount(available),
 			},
-			None => (),
+			_ => MachineError::InvalidAmount { component, amount: unit.amount(amount) },
+		}
+	}
+}
+
+pub struct CoffeeHopper {
+	beans: Reservoir,
+}
+impl Ping for CoffeeHopper {
+	fn name(&self) -> &'static str {
+		"CoffeeHopper"
+	}
+}
+impl Capacity for CoffeeHopper {
+	fn unit(&self) -> Unit {
+		Unit::Ounces
+	}
+	fn level(&self) -> f32 {
+		self.beans.level()
+	}
+	fn capacity(&self) -> f32 {
+		self.beans.capacity()
+	}
+}
+impl Refill for CoffeeHopper {
+	fn refill(&self) -> f32 {
+		self.beans.fill()
+	}
+	fn top_up(&self, amount: f32) -> Result<(), RefillError> {
+		self.beans.add(amount).map_err(|e| e.refill_error(self.name(), self.unit(), amount))
+	}
+}
+impl Default for CoffeeHopper {
+	fn default() -> Self {
+		CoffeeHopper::new()
+	}
+}
+impl CoffeeHopper {
+	/// Creates a hopper filled to capacity.
+	pub fn new() -> Self {
+		CoffeeHopper::with_level(CAPACITY)
+	}
+	/// Creates a hopper holding the given ounces of beans.
+	pub fn with_level(level: f32) -> Self {
+		CoffeeHopper::with_capacity(CAPACITY, level)
+	}
+	/// Creates a hopper that holds the given ounces of beans at most, holding
+	/// the given level to start with.
+	pub fn with_capacity(capacity: f32, level: f32) -> Self {
+		CoffeeHopper { beans: Reservoir::new(level, capacity) }
+	}
+	fn grind_beans(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.ping(timeout)?;
+		if let Some(required) = amount {
+			self.beans.take(required).map_err(|e| e.machine_error(self.name(), self.unit(), required))?;
 		}
 		Ok(())
 	}
 }
 impl ExecJob for CoffeeHopper {
-	fn exec_job(timeout: usize, size: Option<Size>) -> Result<(), String> {
-		CoffeeHopper::grind_beans(timeout, size)
+	fn exec_job(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.grind_beans(timeout, amount)
 	}
 }
 
-pub struct WaterTank;
+pub struct WaterTank {
+	water: Reservoir,
+}
 impl Ping for WaterTank {
-	fn ping(timeout: usize) -> Result<(), String> {
-		let rng = thread_rng().gen_range(2..100);
-		thread::sleep(time::Duration::from_millis(rng));
-		if rng as usize > timeout {
-			Err("WaterTank Component Not Responding".to_string())
-		} else {
-			Ok(())
-		}
+	fn name(&self) -> &'static str {
+		"WaterTank"
 	}
 }
 impl Capacity for WaterTank {
-	fn check_capacity(s: Size) -> Result<(), String> {
-		use Size::*;
-		let s: f32 = match s {
-			Small => 1.0,
-			Medium => 2.0,
-			Large => 3.0,
-		};
-		if s <= WATERAMOUNT {
-			Ok(())	
-		} else {
-			Err("Not enough water in WaterTank".to_string())
-		}
+	fn unit(&self) -> Unit {
+		Unit::FluidOunces
+	}
+	fn level(&self) -> f32 {
+		self.water.level()
+	}
+	fn capacity(&self) -> f32 {
+		self.water.capacity()
+	}
+}
+impl Refill for WaterTank {
+	fn refill(&self) -> f32 {
+		self.water.fill()
+	}
+	fn top_up(&self, amount: f32) -> Result<(), RefillError> {
+		self.water.add(amount).map_err(|e| e.refill_error(self.name(), self.unit(), amount))
+	}
+}
+impl Default for WaterTank {
+	fn default() -> Self {
+		WaterTank::new()
 	}
 }
 impl WaterTank {
-	fn dispense(timeout: usize, size: Option<Size>) -> Result<(), String> {
-		if let Err(e) = WaterTank::ping(timeout) {
-			return Err(e.to_string());
-		}
-		match size {
-			Some(s) => {
-				if let Err(e) = WaterTank::check_capacity(s) {
-					return Err(e.to_string());
-				}
-			},
-			None => (),
+	/// Creates a tank filled to capacity.
+	pub fn new() -> Self {
+		WaterTank::with_level(CAPACITY)
+	}
+	/// Creates a tank holding the given ounces of water.
+	pub fn with_level(level: f32) -> Self {
+		WaterTank::with_capacity(CAPACITY, level)
+	}
+	/// Creates a tank that holds the given ounces of water at most, holding
+	/// the given level to start with.
+	pub fn with_capacity(capacity: f32, level: f32) -> Self {
+		WaterTank { water: Reservoir::new(level, capacity) }
+	}
+	fn dispense(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.ping(timeout)?;
+		if let Some(required) = amount {
+			self.water.take(required).map_err(|e| e.machine_error(self.name(), self.unit(), required))?;
 		}
 		Ok(())
 	}
 }
 impl ExecJob for WaterTank {
-	fn exec_job(timeout: usize, size: Option<Size>) -> Result<(), String> {
-		WaterTank::dispense(timeout, size)
+	fn exec_job(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.dispense(timeout, amount)
 	}
 }
 
 pub struct EspressoPress;
 impl Ping for EspressoPress {
-	fn ping(timeout: usize) -> Result<(), String> {
-		let rng = thread_rng().gen_range(2..100);
-		thread::sleep(time::Duration::from_millis(rng));
-		if rng as usize > timeout {
-			Err("EspressoPress Component Not Responding".to_string())
-		} else {
-			Ok(())
-		}
+	fn name(&self) -> &'static str {
+		"EspressoPress"
 	}
 }
 impl EspressoPress {
-	fn press(timeout: usize) -> Result<(), String> {
-		if let Err(e) = EspressoPress::ping(timeout) {
-			return Err(e.to_string())
-		}
+	fn press(&self, timeout: usize) -> Result<(), MachineError> {
+		self.ping(timeout)?;
 		Ok(())
 	}
 }
 impl ExecJob for EspressoPress {
-	fn exec_job(timeout: usize, _: Option<Size>) -> Result<(), String> {
-		EspressoPress::press(timeout)
+	fn exec_job(&self, timeout: usize, _: Option<f32>) -> Result<(), MachineError> {
+		self.press(timeout)
 	}
 }
 
-pub struct MilkTank;
+pub struct MilkTank {
+	kind: Milk,
+	milk: Reservoir,
+}
 impl Ping for MilkTank {
-	fn ping(timeout: usize) -> Result<(), String> {
-		let rng = thread_rng().gen_range(2..100);
-		thread::sleep(time::Duration::from_millis(rng));
-		if rng as usize > timeout {
-			Err("MilkTank Component Not Responding".to_string())
-		} else {
-			Ok(())
+	fn name(&self) -> &'static str {
+		use Milk::*;
+		match self.kind {
+			Whole => "WholeMilkTank",
+			Skim => "SkimMilkTank",
+			Oat => "OatMilkTank",
+			Almond => "AlmondMilkTank",
+			Soy => "SoyMilkTank",
 		}
 	}
 }
 impl Capacity for MilkTank {
-	fn check_capacity(s: Size) -> Result<(), String> {
-		use Size::*;
-		let s: f32 = match s {
-			Small => 7.0,
-			Medium => 10.0,
-			Large => 13.0,
-		};
-		if s <= MILKAMOUNT {
-			Ok(())
-		} else {
-			Err("Not enough milk in MilkTank".to_string())
-		}
+	fn unit(&self) -> Unit {
+		Unit::FluidOunces
+	}
+	fn level(&self) -> f32 {
+		self.milk.level()
+	}
+	fn capacity(&self) -> f32 {
+		self.milk.capacity()
+	}
+}
+impl Refill for MilkTank {
+	fn refill(&self) -> f32 {
+		self.milk.fill()
+	}
+	fn top_up(&self, amount: f32) -> Result<(), RefillError> {
+		self.milk.add(amount).map_err(|e| e.refill_error(self.name(), self.unit(), amount))
+	}
+}
+impl Default for MilkTank {
+	fn default() -> Self {
+		MilkTank::new(Milk::Whole)
 	}
 }
 impl MilkTank {
-	fn dispense(timeout: usize, size: Option<Size>) -> Result<(), String> {
-		if let Err(e) = MilkTank::ping(timeout) {
-			return Err(e.to_string());
-		}
-		match size {
-			Some(s) => if let Err(e) = MilkTank::check_capacity(s) {
-				return Err(e.to_string());
-			},
-			None => (),
+	/// Creates a tank of the given kind of milk filled to capacity.
+	pub fn new(kind: Milk) -> Self {
+		MilkTank::with_level(kind, CAPACITY)
+	}
+	/// Creates a tank holding the given ounces of a kind of milk.
+	pub fn with_level(kind: Milk, level: f32) -> Self {
+		MilkTank::with_capacity(kind, CAPACITY, level)
+	}
+	/// Creates a tank that holds the given ounces of a kind of milk at most,
+	/// holding the given level to start with.
+	pub fn with_capacity(kind: Milk, capacity: f32, level: f32) -> Self {
+		MilkTank { kind, milk: Reservoir::new(level, capacity) }
+	}
+	pub fn kind(&self) -> Milk {
+		self.kind
+	}
+	fn dispense(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.ping(timeout)?;
+		if let Some(required) = amount {
+			self.milk.take(required).map_err(|e| e.machine_error(self.name(), self.unit(), required))?;
 		}
 		Ok(())
 	}
 }
 impl ExecJob for MilkTank {
-	fn exec_job(timeout: usize, size: Option<Size>) -> Result<(), String> {
-		MilkTank::dispense(timeout, size)
+	fn exec_job(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.dispense(timeout, amount)
 	}
 }
 
 pub struct Frother;
 impl Ping for Frother {
-	fn ping(timeout: usize) -> Result<(), String> {
-		let rng = thread_rng().gen_range(2..100);
-		thread::sleep(time::Duration::from_millis(rng));
-		if rng as usize > timeout {
-			Err("Frother Component Not Responding".to_string())
-		} else {
-			Ok(())
-		}
+	fn name(&self) -> &'static str {
+		"Frother"
 	}
 }
 impl Frother {
-	fn froth(timeout: usize) -> Result<(), String> {
-		if let Err(e) = Frother::ping(timeout) {
-			return Err(e.to_string());
-		}
+	fn froth(&self, timeout: usize) -> Result<(), MachineError> {
+		self.ping(timeout)?;
 		Ok(())
 	}
 }
 impl ExecJob for Frother {
-	fn exec_job(timeout: usize, _: Option<Size>) -> Result<(), String> {
-		Frother::froth(timeout)
+	fn exec_job(&self, timeout: usize, _: Option<f32>) -> Result<(), MachineError> {
+		self.froth(timeout)
+	}
+}
+
+pub struct SyrupPump {
+	syrup: Syrup,
+	bottle: Reservoir,
+}
+impl Ping for SyrupPump {
+	fn name(&self) -> &'static str {
+		use Syrup::*;
+		match self.syrup {
+			Vanilla => "VanillaSyrupPump",
+			Caramel => "CaramelSyrupPump",
+			Hazelnut => "HazelnutSyrupPump",
+		}
+	}
+}
+impl Capacity for SyrupPump {
+	fn unit(&self) -> Unit {
+		Unit::FluidOunces
+	}
+	fn level(&self) -> f32 {
+		self.bottle.level()
+	}
+	fn capacity(&self) -> f32 {
+		self.bottle.capacity()
+	}
+}
+impl Refill for SyrupPump {
+	fn refill(&self) -> f32 {
+		self.bottle.fill()
+	}
+	fn top_up(&self, amount: f32) -> Result<(), RefillError> {
+		self.bottle.add(amount).map_err(|e| e.refill_error(self.name(), self.unit(), amount))
+	}
+}
+impl SyrupPump {
+	/// Creates a pump with a full bottle of the given syrup.
+	pub fn new(syrup: Syrup) -> Self {
+		SyrupPump::with_level(syrup, SYRUP_CAPACITY)
+	}
+	/// Creates a pump whose bottle holds the given ounces of syrup.
+	pub fn with_level(syrup: Syrup, level: f32) -> Self {
+		SyrupPump::with_capacity(syrup, SYRUP_CAPACITY, level)
+	}
+	/// Creates a pump whose bottle holds the given ounces of syrup at most,
+	/// holding the given level to start with.
+	pub fn with_capacity(syrup: Syrup, capacity: f32, level: f32) -> Self {
+		SyrupPump { syrup, bottle: Reservoir::new(level, capacity) }
+	}
+	pub fn syrup(&self) -> Syrup {
+		self.syrup
+	}
+	fn pump(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.ping(timeout)?;
+		if let Some(required) = amount {
+			self.bottle.take(required).map_err(|e| e.machine_error(self.name(), self.unit(), required))?;
+		}
+		Ok(())
 	}
-}
\ No newline at end of file
+}
+impl ExecJob for SyrupPump {
+	fn exec_job(&self, timeout: usize, amount: Option<f32>) -> Result<(), MachineError> {
+		self.pump(timeout, amount)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reservoir_starts_between_empty_and_capacity() {
+		assert_eq!(Reservoir::new(80.0, 64.0).level(), 64.0);
+		assert_eq!(Reservoir::new(-5.0, 64.0).level(), 0.0);
+		assert_eq!(Reservoir::new(f32::NAN, 64.0).level(), 0.0);
+		let broken = Reservoir::new(10.0, f32::NAN);
+		assert_eq!((broken.level(), broken.capacity()), (0.0, 0.0));
+		assert_eq!(Reservoir::new(10.0, -64.0).capacity(), 0.0);
+		assert_eq!(Reservoir::new(10.0, f32::INFINITY).capacity(), 0.0);
+	}
+
+	#[test]
+	fn reservoir_never_goes_below_empty() {
+		let milk = Reservoir::new(10.0, 64.0);
+		assert_eq!(milk.take(4.0), Ok(()));
+		assert_eq!(milk.take(7.0), Err(ReservoirError::NotEnough(6.0)));
+		assert_eq!(milk.level(), 6.0);
+	}
+
+	#[test]
+	fn reservoir_refuses_negative_and_nan_amounts() {
+		let milk = Reservoir::new(10.0, 64.0);
+		assert_eq!(milk.take(-4.0), Err(ReservoirError::InvalidAmount));
+		assert_eq!(milk.add(f32::NAN), Err(ReservoirError::InvalidAmount));
+		assert_eq!(milk.add(60.0), Err(ReservoirError::NoSpace(54.0)));
+		assert_eq!(milk.level(), 10.0);
+	}
+
+	#[test]
+	fn fifth_large_latte_runs_out_of_milk() {
+		let tank = MilkTank::new(Milk::Whole);
+		// a large latte takes 13 oz. of the 64 oz. in the tank.
+		let large_latte = 13.0;
+		for _ in 0..4 {
+			assert!(tank.check_capacity(large_latte).is_ok());
+			assert!(tank.exec_job(1000, Some(large_latte)).is_ok());
+		}
+		assert_eq!(tank.level(), 12.0);
+		assert!(tank.check_capacity(large_latte).is_err());
+		assert!(tank.exec_job(1000, Some(large_latte)).is_err());
+		assert_eq!(tank.level(), 12.0);
+	}
+
+	#[test]
+	fn size_table_looks_up_names_ignoring_case() {
+		let sizes = SizeTable::standard().with("Kids", Size::oz(4.0));
+		assert_eq!(sizes.get(" small "), Some(Size::SMALL));
+		assert_eq!(sizes.get("KIDS"), Some(Size::oz(4.0)));
+		assert_eq!(sizes.get("Venti"), None);
+		assert_eq!(sizes.name_of(Size::LARGE), Some("Large"));
+		assert_eq!(sizes.name_of(Size::oz(20.0)), None);
+	}
+
+	#[test]
+	fn size_table_replaces_a_size_with_the_same_name() {
+		let sizes = SizeTable::standard().with("large", Size::oz(20.0));
+		assert_eq!(sizes.get("Large"), Some(Size::oz(20.0)));
+		assert_eq!(sizes.iter().count(), 3);
+	}
+
+	#[test]
+	fn fixed_backoff_waits_the_same_before_every_attempt() {
+		let policy = RetryPolicy::fixed(3, 10);
+		assert_eq!(policy.delay(1), time::Duration::from_millis(10));
+		assert_eq!(policy.delay(5), time::Duration::from_millis(10));
+	}
+
+	#[test]
+	fn exponential_backoff_doubles_up_to_the_max() {
+		let policy = RetryPolicy::exponential(10, 5, 30);
+		let delays: Vec<u128> = (1..=5).map(|attempt| policy.delay(attempt).as_millis()).collect();
+		assert_eq!(delays, vec![5, 10, 20, 30, 30]);
+		assert_eq!(policy.delay(usize::MAX), time::Duration::from_millis(30));
+	}
+
+	fn fault() -> MachineError {
+		MachineError::MechanicalFault { component: "EspressoPress", reason: "Jammed".to_string() }
+	}
+
+	#[test]
+	fn run_tries_transient_errors_until_one_succeeds() {
+		let mut failures = 2;
+		let (result, attempts) = RetryPolicy::fixed(3, 0).run(|| {
+			if failures > 0 {
+				failures -= 1;
+				Err(fault())
+			} else {
+				Ok("shot")
+			}
+		});
+		assert_eq!(result, Ok("shot"));
+		assert_eq!(attempts, 3);
+	}
+
+	#[test]
+	fn run_gives_up_after_the_last_attempt() {
+		let (result, attempts) = RetryPolicy::fixed(3, 0).run(|| Err::<(), _>(fault()));
+		assert_eq!(result, Err(fault()));
+		assert_eq!(attempts, 3);
+		let (_, attempts) = RetryPolicy::NONE.run(|| Err::<(), _>(fault()));
+		assert_eq!(attempts, 1);
+	}
+
+	#[test]
+	fn run_does_not_retry_errors_that_are_not_transient() {
+		let empty = MachineError::Unavailable { supply: "Oat Milk".to_string() };
+		let (result, attempts) = RetryPolicy::fixed(3, 0).run(|| Err::<(), _>(empty.clone()));
+		assert_eq!(result, Err(empty));
+		assert_eq!(attempts, 1);
+	}
+
+	#[test]
+	fn policies_override_components_by_name() {
+		let policies = RetryPolicies::new(RetryPolicy::fixed(3, 10)).with("watertank", RetryPolicy::NONE);
+		assert_eq!(policies.get("WaterTank"), RetryPolicy::NONE);
+		assert_eq!(policies.get("EspressoPress"), RetryPolicy::fixed(3, 10));
+	}
+}
@@ -1,8 +1,375 @@
-// mod ingredient_based;
-mod message_based;
-mod machine_components;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+use futures::executor::block_on;
+use espresso_maker::batch;
+use espresso_maker::config::MachineConfig;
+use espresso_maker::http::{self, Api, Server};
+use espresso_maker::ingredient_based::{self, Temperatures};
+use espresso_maker::logging::{Console, JsonLines, Level, Logger};
+use espresso_maker::machine_components::{Size, SizeTable};
+use espresso_maker::message_based::{Cup, Machine, Settings};
+use espresso_maker::recipe::Menu;
+use espresso_maker::shift::{self, Shift, Timings};
+use espresso_maker::simulation::Simulation;
+use espresso_maker::units;
+
+const USAGE: &str = "\
+Usage: espresso_maker [--config FILE] [--seed N] [--log FILE] [--log-level LEVEL] [COMMAND]
+
+Runs an espresso machine, built from the machine definition in FILE or the
+standard machine. With a COMMAND, runs it and waits for any orders it placed
+before exiting. Without one, reads commands from the terminal until `quit`.
+With a seed, the machine runs on a virtual clock and every run is the same.
+The machine logs to the terminal at LEVEL (debug, info, warn or error; info
+unless given another) and, with a log FILE, every record as JSON lines.";
+
+const HELP: &str = "\
+Commands:
+  order NAME SIZE DRINK  Order a drink, e.g. `order Josh Large oat latte`.
+                         Put a name or size with spaces in quotes.
+  queue                  List every order and how far along it is.
+  wait [ID]              Wait for an order, or every order, to finish.
+  menu                   List the drinks and sizes on offer.
+  inventory              Show how much each container holds.
+  refill NAME|all        Fill a container, or every container, to capacity.
+  diagnostics            Ping every component once and report its answer.
+  metrics                Show the machine's metrics in the Prometheus text
+                         format, as `serve` gives them at /metrics.
+  batch ORDERS [RESULTS] Replay a CSV or JSON lines file of orders, writing
+                         each order's status and timing to RESULTS, or to
+                         the terminal as CSV.
+  serve [PORT]           Serve the HTTP ordering API on localhost, on port
+                         8080 unless given another, until `quit`.
+  shift MACHINES ORDERS_PER_HOUR HOURS
+                         Simulate a shift of orders arriving at random on
+                         some number of machines and report the throughput,
+                         waits and how busy each stage was, e.g. `shift 2
+                         90 8` to see if two machines keep up with 90 orders
+                         an hour over a day. Stages take as long as the
+                         machine definition's [stages] say.
+  handmade               Make a latte by hand, one ingredient at a time, at
+                         the machine definition's temperatures.
+  demo                   Order the five demo drinks and wait for them.
+  help                   Show this list.
+  quit                   Finish the orders in progress and exit.";
+
+// The machine, what orders are taken from, and the HTTP API if it is being
+// served.
+struct Counter {
+    machine: Arc<Machine>,
+    menu: Menu,
+    sizes: SizeTable,
+    seed: Option<u64>,
+    timings: Timings,
+    temperatures: Temperatures,
+    server: Mutex<Option<Server>>,
+}
+
+impl Counter {
+    fn open(config: &MachineConfig, seed: Option<u64>) -> Self {
+        let simulation = seed.map_or_else(Simulation::real, Simulation::seeded);
+        let settings = Settings { simulation, ..config.settings() };
+        units::set_unit_system(config.units);
+        Counter {
+            machine: Arc::new(Machine::start_with(config.components(), settings)),
+            menu: config.menu(),
+            sizes: config.sizes.clone(),
+            seed,
+            timings: config.timings,
+            temperatures: config.temperatures,
+            server: Mutex::new(None),
+        }
+    }
+
+    // Runs a command, returning false once the counter should close.
+    fn run(&self, words: &[String]) -> bool {
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (command.to_lowercase(), args),
+            None => return true,
+        };
+        match (command.as_str(), args) {
+            ("order", [name, size, drink @ ..]) if !drink.is_empty() => self.order(name, size, &drink.join(" ")),
+            ("order", _) => println!("Usage: order NAME SIZE DRINK"),
+            ("queue" | "list", []) => self.queue(),
+            ("wait", []) => {
+                self.machine.wait_all();
+                self.queue();
+            }
+            ("wait", [id]) => match id.trim_start_matches('#').parse() {
+                Ok(id) => match self.machine.wait(id) {
+                    Some(status) => println!("#{} {}", id, status),
+                    None => println!("No order #{}", id),
+                },
+                Err(_) => println!("{} is not an order number", id),
+            },
+            ("menu", []) => self.show_menu(),
+            ("inventory", []) => {
+                for (name, level) in self.machine.inventory() {
+                    println!("{}: {}", name, level);
+                }
+            }
+            ("refill", [name]) if name.eq_ignore_ascii_case("all") => {
+                for (name, _) in self.machine.inventory() {
+                    self.refill(name);
+                }
+            }
+            ("refill", [name]) => self.refill(name),
+            ("refill", _) => println!("Usage: refill NAME|all"),
+            ("diagnostics", []) => {
+                for (name, answer) in self.machine.diagnostics() {
+                    match answer {
+                        Ok(took) => println!("{}: OK, answered in {} ms", name, took.as_millis()),
+                        Err(e) => println!("{}: {}", name, e),
+                    }
+                }
+            }
+            ("metrics", []) => print!("{}", self.machine.metrics()),
+            ("batch", [orders]) => self.batch(orders, None),
+            ("batch", [orders, results]) => self.batch(orders, Some(results)),
+            ("batch", _) => println!("Usage: batch ORDERS [RESULTS]"),
+            ("serve", []) => self.serve(8080),
+            ("serve", [port]) => match port.parse() {
+                Ok(port) => self.serve(port),
+                Err(_) => println!("{} is not a port", port),
+            },
+            ("shift", [machines, rate, hours]) => match (machines.parse(), rate.parse::<f32>(), hours.parse::<f32>()) {
+                (Ok(machines), Ok(rate), Ok(hours))
+                    if machines > 0 && rate.is_finite() && rate > 0.0 && hours.is_finite() && hours > 0.0 =>
+                {
+                    self.shift(machines, rate, hours)
+                }
+                _ => println!("Usage: shift MACHINES ORDERS_PER_HOUR HOURS, each a number more than 0"),
+            },
+            ("shift", _) => println!("Usage: shift MACHINES ORDERS_PER_HOUR HOURS"),
+            ("handmade", []) => ingredient_based::ingredient_based_main_at(self.temperatures),
+            ("demo", []) => self.demo(),
+            ("help", _) => println!("{}", HELP),
+            ("quit" | "exit", []) => return false,
+            _ => println!("Unknown command `{}`, try `help`", words.join(" ")),
+        }
+        true
+    }
+
+    fn order(&self, name: &str, size: &str, drink: &str) {
+        let cup = match self.sizes.get(size) {
+            None => return println!("{} is not a size on offer, try `menu`", size),
+            Some(s) => match Cup::order(&self.menu, drink, s, name.to_string()) {
+                None => return println!("{} is not on the menu, try `menu`", drink),
+                Some(cup) => cup,
+            },
+        };
+        match self.machine.submit(cup) {
+            Ok(handle) => println!("Order #{} taken: {} {} for {}", handle.id(), size, drink, name),
+            Err(e) => println!("Cannot make {}'s Coffee! {}", name, e),
+        }
+    }
+
+    fn queue(&self) {
+        let orders = self.machine.orders();
+        if orders.is_empty() {
+            println!("No orders yet");
+        }
+        for (id, cup, status) in orders {
+            let size = self.sizes.name_of(cup.size()).map_or_else(|| cup.size().to_string(), |s| s.to_string());
+            println!("#{} {}'s {} {}: {}", id, cup.client(), size, cup.recipe().name(), status);
+        }
+    }
+
+    fn show_menu(&self) {
+        println!("Drinks: {}", self.menu.names().collect::<Vec<_>>().join(", "));
+        println!("Any drink can start with a milk or syrup, e.g. `oat latte` or `vanilla cappuccino`.");
+        let sizes: Vec<String> = self.sizes.iter().map(|(name, size)| format!("{} ({})", name, size)).collect();
+        println!("Sizes: {}", sizes.join(", "));
+    }
+
+    fn refill(&self, name: &str) {
+        match self.machine.refill(name) {
+            Some(amount) => println!("Refilled {} with {}", name, amount),
+            None => println!("The machine has no container called {}", name),
+        }
+    }
+
+    fn batch(&self, orders: &str, results: Option<&String>) {
+        let orders = match batch::read_orders(orders) {
+            Ok(orders) => orders,
+            Err(e) => return println!("Cannot replay {}: {}", orders, e),
+        };
+        let replayed = batch::replay(&self.machine, &self.menu, &self.sizes, &orders);
+        let count = |status| replayed.iter().filter(|r| r.outcome.status() == status).count();
+        println!("{} ready, {} failed, {} rejected", count("ready"), count("failed"), count("rejected"));
+        match results {
+            Some(path) => match batch::write_results(path, &replayed) {
+                Ok(()) => println!("Results written to {}", path),
+                Err(e) => println!("Cannot write {}: {}", path, e),
+            },
+            None => print!("{}", batch::results_csv(&replayed)),
+        }
+    }
+
+    fn serve(&self, port: u16) {
+        let mut server = self.server.lock().unwrap();
+        if let Some(running) = server.as_ref() {
+            return println!("Already serving on http://{}", running.local_addr());
+        }
+        let api = Api::new(Arc::clone(&self.machine), self.menu.clone(), self.sizes.clone());
+        match http::serve(Arc::new(api), port) {
+            Ok(started) => {
+                println!("Serving the ordering API on http://{}", started.local_addr());
+                *server = Some(started);
+            }
+            Err(e) => println!("Cannot serve on port {}: {}", port, e),
+        }
+    }
+
+    // Stops serving the HTTP API and the machine, once the orders in progress
+    // are finished.
+    fn close(self) {
+        if let Some(server) = self.server.into_inner().unwrap() {
+            server.stop();
+        }
+        if let Ok(machine) = Arc::try_unwrap(self.machine) {
+            block_on(machine.shutdown());
+        }
+    }
+
+    // Simulates a shift on its own time, with the counter's seed if it has
+    // one so that the same shift can be run on different numbers of machines.
+    fn shift(&self, machines: usize, orders_per_hour: f32, hours: f32) {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let schedule = shift::arrivals(seed, orders_per_hour, hours, &self.menu, &self.sizes);
+        println!("{}", Shift::new(machines).with_timings(self.timings).run(&schedule));
+    }
+
+    // The five demo orders, made and awaited one after the other.
+    fn demo(&self) {
+        let sizes = self.sizes.clone()
+            .with("Kids", Size::oz(4.0))
+            .with("Extra Large", Size::oz(20.0));
+        let orders = [
+            ("Josh", "Latte", "Medium"),
+            ("Sharon", "Americano", "Large"),
+            ("Moobly", "Oat Cappuccino", "Kids"),
+            ("Tosh", "Vanilla Flat White", "Extra Large"),
+            ("Mary", "Doppio", "Small"),
+        ];
+        let mut handles = Vec::new();
+        for (name, drink, size) in orders.iter() {
+            match sizes.get(size).and_then(|s| Cup::order(&self.menu, drink, s, name.to_string())) {
+                None => println!("A {} {} is not on the menu, cannot make {}'s Coffee!", size, drink, name),
+                Some(cup) => match self.machine.submit(cup) {
+                    Ok(handle) => handles.push((handle, name)),
+                    Err(e) => println!("{}\nCannot make {}'s Coffee!", e, name),
+                },
+            }
+        }
+        let ids: Vec<usize> = handles.iter().map(|(handle, _)| handle.id()).collect();
+        block_on(async {
+            for (handle, name) in handles {
+                match handle.await {
+                    Ok(cup) => println!("{}'s Coffee is ready: {}", name, cup),
+                    Err(e) => println!("Cannot make {}'s Coffee! {}", name, e),
+                }
+            }
+        });
+        // from the first order being started to the last one finishing.
+        let timings: Vec<_> = ids.iter().filter_map(|id| self.machine.timing(*id)).collect();
+        let started = timings.iter().map(|t| t.started).min().unwrap_or_default();
+        let finished = timings.iter().filter_map(|t| t.finished).max().unwrap_or(started);
+        println!("Took {} ms", finished.saturating_sub(started).as_millis());
+    }
+}
+
+// Splits a line into words, keeping anything in double quotes together.
+fn split_words(line: &str) -> Vec<String> {
+    let (mut words, mut word, mut quoted) = (Vec::new(), String::new(), false);
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2)
+}
 
 fn main() {
-    // ingredient_based::ingredient_based_main();
-    message_based::message_based_main();
-}
\ No newline at end of file
+    let (mut config, mut seed, mut command) = (MachineConfig::standard(), None, Vec::new());
+    let (mut log_file, mut log_level) = (None, Level::Info);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" if command.is_empty() => {
+                let path = args.next().unwrap_or_else(|| fail("--config needs a file"));
+                config = MachineConfig::load(&path).unwrap_or_else(|e| fail(&e.to_string()));
+            }
+            "--seed" if command.is_empty() => {
+                let n = args.next().unwrap_or_else(|| fail("--seed needs a number"));
+                seed = Some(n.parse().unwrap_or_else(|_| fail(&format!("{} is not a seed, expected a whole number", n))));
+            }
+            "--log" if command.is_empty() => {
+                let path = args.next().unwrap_or_else(|| fail("--log needs a file"));
+                log_file = Some(JsonLines::open(&path).unwrap_or_else(|e| fail(&format!("Cannot open {}: {}", path, e))));
+            }
+            "--log-level" if command.is_empty() => {
+                let level = args.next().unwrap_or_else(|| fail("--log-level needs a level"));
+                log_level = level.parse().unwrap_or_else(|e: String| fail(&e));
+            }
+            "-h" | "--help" if command.is_empty() => {
+                println!("{}\n\n{}", USAGE, HELP);
+                return;
+            }
+            _ => command.push(arg),
+        }
+    }
+    let mut logger = Logger::new().with_sink(log_level, Console);
+    if let Some(log_file) = log_file {
+        logger = logger.with_sink(Level::Debug, log_file);
+    }
+    logger.install();
+    let counter = Counter::open(&config, seed);
+    if command.is_empty() {
+        println!("Espresso machine ready, type `help` for commands");
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            io::stdout().flush().ok();
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) if counter.run(&split_words(&line)) => (),
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Cannot read the command: {}", e);
+                    break;
+                }
+            }
+        }
+    } else if command[0].eq_ignore_ascii_case("serve") {
+        // serve until the process is stopped.
+        counter.run(&command);
+        if let Some(server) = counter.server.lock().unwrap().take() {
+            server.wait();
+        }
+    } else {
+        counter.run(&command);
+        // wait for an order the command placed before the machine stops.
+        if command[0].eq_ignore_ascii_case("order") && !counter.machine.orders().is_empty() {
+            counter.machine.wait_all();
+            counter.queue();
+        }
+    }
+    counter.close();
+}
@@ -1,31 +1,47 @@
 use std::thread;
 use waitgroup::WaitGroup;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
 use std::fmt;
-use std::boxed::Box;
 use std::string::String;
 use std::ops;
-use futures::executor::block_on;
+use std::time::Duration;
+use crate::config::MachineConfig;
+use crate::events::{Event, EventBus, EventKind};
+use crate::logging;
+use crate::metrics;
 use crate::machine_components::*;
+use crate::recipe::*;
+use crate::simulation::{self, Clock, Simulation, Stream};
+use crate::units::Amount;
 
 type S<T> = mpsc::Sender<T>;
-type R<T> = mpsc::Receiver<T>;
-type ChannelData = (usize, Option<Size>);
-const TIMEOUT: usize = 101;
+// an order's cup id, size and recipe, why it failed if it has, and when it
+// was passed on, on the clock of the stage that passed it.
+type ChannelData = (usize, Option<Size>, Arc<Recipe>, Option<OrderError>, Duration);
 
-struct Cup {
+/// A customer's order, and the cup it is made in.
+#[derive(Clone)]
+pub struct Cup {
 	// size is used to check if there are enough ingredients for order.
 	size: Size,
-	contents: Box<Vec<Ingredient>>,
+	recipe: Arc<Recipe>,
+	contents: Vec<Ingredient>,
 	client: String,
+	// the components that took more than one attempt while the cup was made.
+	attempts: Vec<(&'static str, usize)>,
 }
 impl fmt::Display for Cup {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		use Ingredient::*;
-		let contents: String = self.contents.iter()
-			.map(|i| match i {Espresso => " Espresso ", Milk => " Milk "})
-			.fold(String::from(""), |acc, i| acc + i);
-		write!(f, "Size: {}, Contents: {}", self.size, contents)
+		let contents: Vec<String> = self.contents.iter()
+			.map(|i| i.to_string())
+			.collect();
+		let contents = if contents.is_empty() { "Empty".to_string() } else { contents.join(", ") };
+		write!(f, "{} {} for {}, Contents: {}", self.size, self.recipe.name(), self.client, contents)
 	}
 }
 impl ops::Add<Ingredient> for Cup {
@@ -37,60 +53,83 @@ impl ops::Add<Ingredient> for Cup {
 		c.push(i);
 		Self {
 			size: self.size,
+			recipe: self.recipe,
 			contents: c,
 			client: self.client,
+			attempts: self.attempts,
 		}
 	}
 }
 impl Cup {
-	fn new(s: Size, c: String) -> Self {
+	/// Creates a cup for a drink on the menu, or None if the menu doesn't
+	/// have a drink by that name.
+	pub fn order(menu: &Menu, drink: &str, s: Size, c: String) -> Option<Self> {
+		menu.get(drink).map(|r| Cup::new(s, r, c))
+	}
+	pub fn new(s: Size, r: Arc<Recipe>, c: String) -> Self {
 		Cup {
 			size: s,
-			contents: Box::new(Vec::<Ingredient>::new()),
+			recipe: r,
+			contents: Vec::<Ingredient>::new(),
 			client: c,
+			attempts: Vec::new(),
 		}
 	}
-	fn add_ingredient(mut self, i: Ingredient) {
-		self.contents.push(i);
+	pub fn size(&self) -> Size {
+		self.size
+	}
+	pub fn recipe(&self) -> &Arc<Recipe> {
+		&self.recipe
+	}
+	pub fn contents(&self) -> &[Ingredient] {
+		&self.contents
+	}
+	pub fn client(&self) -> &str {
+		&self.client
+	}
+	/// The components that took more than one attempt while the cup was
+	/// made, and how many attempts each took.
+	pub fn attempts(&self) -> &[(&'static str, usize)] {
+		&self.attempts
 	}
 }
 
 /// This macro has two definitions that test the readiness of a component,
 /// i.e., checking if a component in the machine is able to be pinged and/or
 /// if a component that is a container for material has enough material to
-/// complete the task.<br>
+/// complete the task. Both work on references and trait objects alike. The
+/// component is pinged under a RetryPolicy, and a check that passes evaluates
+/// to the number of attempts the ping took.<br>
 /// These two definitions include:<br>
 /// 1. A check on a component that implements the Ping trait. This invocation
-/// requires the following:<br>
-/// 1a. An expression that evaluates to a usize representing a timeout for the
-/// machine component to respond in milliseconds.<br>
-/// 1b. A type that represents the machine component that implements the Ping
-/// interface and it's associated functions.<br>
+///    requires the following:<br>
+///    1a. An expression that evaluates to the component, e.g. a
+///    `&dyn ExecJob`.<br>
+///    1b. An expression that evaluates to a usize representing a timeout for
+///    the machine component to respond in milliseconds.<br>
+///    1c. An expression that evaluates to a RetryPolicy, following `retry`
+///    after the parentheses.<br>
 /// 2. A check on a component that implements the Ping and Capacity trait. This
-/// invocation requires the following:<br>
-/// 2a. An expression that evaluates to a usize representing a timeout for the
-/// machine component to respond in milliseconds.<br>
-/// 2b. An expression that evaluates to a Size enum representing the size of a
-/// customer's cup.<br>
-/// 2c. A type that represents the machine component that implements the Ping
-/// interface and it's associated functions.
+///    invocation requires the following:<br>
+///    2a. An expression that evaluates to the component holding the material,
+///    e.g. a `&dyn Container`.<br>
+///    2b. An expression that evaluates to a usize representing a timeout for
+///    the machine component to respond in milliseconds.<br>
+///    2c. An expression that evaluates to a Size enum representing the size of
+///    a customer's cup.<br>
+///    2d. An expression that evaluates to a RetryPolicy, following `retry`
+///    after the parentheses.
 macro_rules! check_machine {
-	(<$m:ty>::($t_o:expr)) => {
-		if let Result::Err(e) = <$m>::ping($t_o) {
-			Err(e)
-		} else {
-			Ok(())
+	($c:expr => ($t_o:expr) retry $policy:expr) => {
+		match $policy.run(|| $c.ping($t_o)) {
+			(Result::Err(e), _) => Err(e),
+			(_, attempts) => Ok(attempts),
 		}
 	};
-	(<$m:ty>::($t_o:expr, $s:expr)) => {
-		if let Result::Err(e) = <$m>::ping($t_o) {
-			Err(e)
-		} else {
-			if let Result::Err(e) = <$m>::check_capacity($s) {
-				Err(e)
-			} else {
-				Ok(())
-			}
+	($c:expr => ($t_o:expr, $s:expr) retry $policy:expr) => {
+		match $policy.run(|| $c.ping($t_o)) {
+			(Result::Err(e), _) => Err(e),
+			(_, attempts) => $c.check_capacity($s).map(|()| attempts),
 		}
 	};
 }
@@ -99,162 +138,1167 @@ macro_rules! check_machine {
 /// library of the type ChannelData (defined at the top of the file).
 /// This definition requires the following:
 /// 1. An identifier that will be the name of the send channel being returned
-/// from the channel constructor function.
+///    from the channel constructor function.
 /// 2. An identifier that will be the name of the receiver channel being
-/// returned from the channel constructor function.
+///    returned from the channel constructor function.
 macro_rules! create_channel {
 	($send_name: ident, $recv_name: ident) => {
 		let ($send_name, $recv_name) = mpsc::channel::<ChannelData>();
 	};
 }
 /// This macro contains two definitions for creating channel pipelines,
-/// i.e. a function that runs on a thread that passes data through channels
-/// to other functions running on another thread. These two pipelines are the
-/// ending pipeline and the connector pipeline.<br>
+/// i.e. a function that passes data through channels to other functions,
+/// each running on a thread of its own or stepped in turn. These two
+/// pipelines are the ending pipeline and the connector pipeline. The generated
+/// function takes the components of its stage as its first argument, each as
+/// a shared trait object paired with the Measure of how much of its job an
+/// order needs, e.g. the heat_milk stage has a tank for every kind of milk and
+/// only draws from the one an order's recipe uses. Its orders come from an
+/// iterator over its receiver, which either blocks on the receiver or only
+/// takes the orders waiting on it. The function also takes a sender to the
+/// assemble_cups stage, which it tells when an order's branch is finished,
+/// the machine's Orders, which it tells when an order has failed, and the
+/// machine's Timeouts, which are looked up under the name of the function for
+/// every order so that they can be changed while the machine runs. An
+/// order that fails is passed on with its failure rather than dropped, so the
+/// pipes after it skip the order and its branch still ends at assemble_cups.
+/// The stage waits on its clock for each order to reach it, and gives how
+/// many orders it took.<br>
 /// 1. The end pipeline takes the following arguments:<br>
-/// 1a. An identifier representing the name of the function.<br>
-/// 1b. An identifier representing the name of the receiver channel that the 
-/// pipe will be taking data from.<br>
-/// 1c. A type representing a component struct that implements the ExecJob trait.<br>
-/// 1d. An expression that represents a usize typed timeout<br>
-/// 1e. An expression that represents a string that will be printed as a
-/// success message. Can pass in a template to print out the cup ID.<br>
+///    1a. An identifier representing the name of the function.<br>
+///    1b. An identifier representing the name of the receiver channel that
+///    the pipe will be taking data from.<br>
+///    1c. A path to the Branch of the machine the pipe is on, following a
+///    `=>`, e.g. `=> Branch::Espresso`.<br>
+///    1d. An expression that represents a string that will be logged as a
+///    success message, along with the cup ID, stage and how long the stage
+///    took. Can pass in a template to put the cup ID in the message.<br>
 /// 2. the connector pipeline takes the following arguments (Mostly similar to
-/// the end pipeline):<br>
-/// 2a. An identifier representing the name of the function.<br>
-/// 2b. An identifier representing the name of the receiver channel that the 
-/// pipe will be taking data from.<br>
-/// 2c. An identifier representing the name of the sender channel that the pipe
-/// will be sending data to the next pipe.<br>
-/// 2d. A type representing a component struct that implements the ExecJob trait.<br>
-/// 2e. An expression that represents a usize typed timeout<br>
-/// 2f. An expression that represents a string that will be printed as a
-/// success message. Can pass in a template to print out the cup ID.<br>
+///    the end pipeline):<br>
+///    2a. An identifier representing the name of the function.<br>
+///    2b. An identifier representing the name of the receiver channel that
+///    the pipe will be taking data from.<br>
+///    2c. An identifier representing the name of the sender channel that the
+///    pipe will be sending data to the next pipe. It can be followed by
+///    `if` and a Measure, in which case an order is only sent on when its
+///    recipe needs some of that Measure, e.g. `froth_send if Measure::Foam`.
+///    Otherwise the order ends at this pipe.<br>
+///    2d. A path to the Branch of the machine the pipe is on, following a
+///    `=>`. An order's branch ends here if it isn't sent on.<br>
+///    2e. An expression that represents a string that will be logged as a
+///    success message, as in 1d.<br>
 macro_rules! create_pipeline {
-	($func_name: ident <$component: ty> ($recv_name: ident) { $timeout: expr, $success_msg: expr }) => {
-		fn $func_name($recv_name: R<ChannelData>, worker: waitgroup::Worker) {
-			while let Ok((cup_id, size)) = $recv_name.recv() {
-				match <$component>::exec_job($timeout, size) {
-					Err(e) => println!("{}", e),
-					_ => println!($success_msg, cup_id),
+	($func_name: ident ($recv_name: ident) => $branch: path { $success_msg: expr }) => {
+		fn $func_name(components: &Jobs, $recv_name: impl Iterator<Item = ChannelData>, assemble_send: &S<Assembly>, orders: &Orders, timeouts: &RwLock<Timeouts>) -> usize {
+			let mut taken = 0;
+			for data in $recv_name {
+				taken += 1;
+				simulation::wait_until(data.4);
+				let started = now();
+				let failure = run_stage(components, orders, timeouts, stringify!($func_name), &data);
+				if failure.is_none() {
+					stage_finished(format!($success_msg, data.0), data.0, stringify!($func_name), now().saturating_sub(started));
 				}
+				end_branch(assemble_send, orders, data.0, $branch, stringify!($func_name), failure);
 			}
-			drop(worker);
+			taken
 		}
 	};
-	($func_name: ident <$component: ty> ($recv_name: ident, $send_name: ident) { $timeout: expr, $success_msg: expr }) => {
-		fn $func_name($recv_name: R<ChannelData>, $send_name: S<ChannelData>, worker: waitgroup::Worker) {
-			while let Ok((cup_id, size)) = $recv_name.recv() {
-				match <$component>::exec_job($timeout, size) {
-					Err(e) => println!("{}", e),
-					_ => match $send_name.send((cup_id, size)) {
-						Ok(()) => println!($success_msg, cup_id),
-						Err(e) => println!("{}", e),
-					}
+	($func_name: ident ($recv_name: ident, $send_name: ident $(if $route: expr)?) => $branch: path { $success_msg: expr }) => {
+		fn $func_name(components: &Jobs, $recv_name: impl Iterator<Item = ChannelData>, $send_name: &S<ChannelData>, assemble_send: &S<Assembly>, orders: &Orders, timeouts: &RwLock<Timeouts>) -> usize {
+			let mut taken = 0;
+			for data in $recv_name {
+				taken += 1;
+				let forward = true $(&& data.1.is_none_or(|s| $route.of(&data.2, s) > 0.0))?;
+				simulation::wait_until(data.4);
+				let started = now();
+				let failure = run_stage(components, orders, timeouts, stringify!($func_name), &data);
+				let (cup_id, size, recipe, _, _) = data;
+				if failure.is_none() {
+					stage_finished(format!($success_msg, cup_id), cup_id, stringify!($func_name), now().saturating_sub(started));
+				}
+				if !forward {
+					end_branch(assemble_send, orders, cup_id, $branch, stringify!($func_name), failure);
+				} else if let Err(mpsc::SendError((_, _, _, failure, _))) = $send_name.send((cup_id, size, recipe, failure, now())) {
+					let failure = failure.unwrap_or_else(|| OrderError::disconnected(stringify!($func_name)));
+					report_failure(cup_id, &failure);
+					end_branch(assemble_send, orders, cup_id, $branch, stringify!($func_name), Some(failure));
 				}
 			}
-			drop(worker);
+			taken
 		}
 	};
 }
 
-fn run_checks(t_o: usize, s: Size) -> [Result<(), String>; 5] {
-	[
-		check_machine!(<CoffeeHopper>::(t_o, s)),
-		check_machine!(<WaterTank>::(t_o, s)),
-		check_machine!(<EspressoPress>::(t_o)),
-		check_machine!(<MilkTank>::(t_o, s)),
-		check_machine!(<Frother>::(t_o))
-	]
-}
-
-fn start_coffee_maker(hopper_send: &S<ChannelData>, milk_send: &S<ChannelData>, timeout: usize, client_id: usize, size: Size) {
-	if timeout < 50 {
-		println!("Client {} Start Coffee Timeout!", client_id);
-	}
-	match hopper_send.send((client_id, Option::Some(size))) {
-		Ok(()) => println!("Client {} Coffee Beans Started!", client_id),
-		Err(e) => println!("Error Starting Client {} Coffee Beans!\n{}", client_id, e),
-	}
-	match milk_send.send((client_id, Option::Some(size))) {
-		Ok(()) => println!("Client {} Milk Started!", client_id),
-		Err(e) => println!("Error starting Client {} Milk!\n{}", client_id, e),
-	}
-}
-
-create_pipeline!(grind_coffee<CoffeeHopper>(hopper_recv, water_send) { TIMEOUT, "Coffee Ground for Client {}!" });
-create_pipeline!(dispense_water<WaterTank>(water_recv, press_send) { TIMEOUT, "Water Dispensed for Client {}!" });
-create_pipeline!(press_espresso<EspressoPress>(press_recv) { TIMEOUT, "Espresso Pressed for Client {}!" });
-create_pipeline!(heat_milk<MilkTank>(milk_recv, froth_send) { TIMEOUT, "Milk heated for Client {}!" });
-create_pipeline!(froth_milk<Frother>(froth_recv) { TIMEOUT, "Milk frothed for Client {}!" });
-
-async fn do_five_times() {
-	// create a workgroup that will generate workers that will be passed to
-	// threads. These workers will essentially block the exit of the main
-	// thread until the workers passed to the threads are deallowcated.
-	let wg = WaitGroup::new();
-	let grind_beans_worker = wg.worker();
-	let dispense_water_worker = wg.worker();
-	let press_espresso_worker = wg.worker();
-	let heat_milk_worker = wg.worker();
-	let froth_milk_worker = wg.worker();
-	// create a set of channels that will be passing data from thread to thread
-	create_channel!(grind_send, grind_recv);
-	create_channel!(water_send, water_recv);
-	create_channel!(press_send, press_recv);
-	create_channel!(milk_send, milk_recv);
-	create_channel!(froth_send, froth_recv);
-	// create a vector of cups that will be filled with coffee.
-	let cups = ["Josh", "Sharon", "Moobly", "Tosh", "Mary"]
-		.map(|name| { return Cup::new(Size::Medium, name.to_string()); });
-	// create a vector of JoinHandles that will be executed using join
-	// and return if the thread was created successfully.
-	let threads = vec![
-		thread::spawn(move || grind_coffee(grind_recv, water_send, grind_beans_worker)),
-		thread::spawn(move || dispense_water(water_recv, press_send, dispense_water_worker)),
-		thread::spawn(move || press_espresso(press_recv, press_espresso_worker)),
-		thread::spawn(move || heat_milk(milk_recv, froth_send, heat_milk_worker)),
-		thread::spawn(move || froth_milk(froth_recv, froth_milk_worker)),
-	];
-	// Attempt to start each of the threads. If there's an error starting a
-	// thread, print out the error and return early.
-	for t in threads {
-		if let Err(e) = t.join() {
-			if let Some(e) = e.downcast_ref::<&'static str>() {
-				println!("Error starting thread: {}", e);
+// Logs a stage finishing its part of an order, and counts how long it took.
+fn stage_finished(message: String, cup_id: usize, stage: &'static str, took: Duration) {
+	metrics::global().stage_took(stage, took);
+	logging::info(message)
+		.with("cup_id", cup_id)
+		.with("stage", stage)
+		.with("duration_ms", took)
+		.log();
+}
+
+// Logs the failure of a stage on an order, along with the component that
+// failed and the kind of error, and counts it against the component.
+fn report_failure(cup_id: usize, failure: &OrderError) {
+	metrics::global().failed(failure.error.component().unwrap_or(failure.stage), failure.error.kind());
+	let mut record = logging::error(failure.error.to_string())
+		.with("cup_id", cup_id)
+		.with("stage", failure.stage);
+	if let Some(component) = failure.error.component() {
+		record = record.with("component", component);
+	}
+	record.with("error_kind", failure.error.kind()).with("attempts", failure.attempts).log();
+}
+
+/// The components of a pipeline stage, each paired with the Measure of how
+/// much of its job an order needs and the RetryPolicy its job is run under.
+type Jobs = Vec<(Component, Measure, RetryPolicy)>;
+
+/// Runs the job of every component of a stage that the order needs, stopping
+/// at the first one that fails. Without a size, every component's job is run
+/// without drawing any material. The material drawn from each container is
+/// noted on the order, so that it can be put back if the order fails, as are
+/// the components that took more than one attempt. Each component is given
+/// its timeout in the stage. A job that fails gives its error along with the
+/// attempts made at it.
+fn run_jobs(components: &Jobs, timeouts: &Timeouts, stage: &str, cup_id: usize, size: Option<Size>, recipe: &Recipe, orders: &Orders) -> Result<(), (MachineError, usize)> {
+	for (component, measure, retry) in components {
+		let amount = size.map(|s| measure.of(recipe, s));
+		if amount.is_none_or(|a| a > 0.0) {
+			let timeout = timeouts.get(component.name(), Some(stage));
+			let (result, attempts) = match component {
+				Component::Device(d) => retry.run(|| d.exec_job(timeout, amount)),
+				Component::Container(c) => retry.run(|| c.exec_job(timeout, amount)),
+			};
+			if attempts > 1 {
+				logging::warn(format!("{} took {} attempts for Client {}", component.name(), attempts, cup_id))
+					.with("cup_id", cup_id)
+					.with("stage", stage)
+					.with("component", component.name())
+					.with("attempts", attempts)
+					.log();
+				orders.retried(cup_id, component.name(), attempts);
+			}
+			result.map_err(|e| (e, attempts))?;
+			if let (Component::Container(c), Some(amount)) = (component, amount) {
+				orders.drawn(cup_id, c, amount);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Runs a stage's jobs for an order, returning why the order failed if it
+/// has. An order that already failed, either further up its branch or on a
+/// sibling branch, is passed along without running any jobs. If a job fails
+/// here, the order is failed straight away so that its sibling branches stop
+/// too. The timeouts are read once per order, so a change to them applies from
+/// the next order on. The stage starting and finishing on the order are
+/// published as events.
+fn run_stage(components: &Jobs, orders: &Orders, timeouts: &RwLock<Timeouts>, stage: &'static str, data: &ChannelData) -> Option<OrderError> {
+	let (cup_id, size, recipe, failure, _) = data;
+	if let Some(failure) = failure.clone().or_else(|| orders.failure(*cup_id)) {
+		return Some(failure);
+	}
+	let timeouts = timeouts.read().unwrap().clone();
+	orders.stage(*cup_id, EventKind::StageStarted(stage));
+	match run_jobs(components, &timeouts, stage, *cup_id, *size, recipe, orders) {
+		Ok(()) => {
+			orders.stage(*cup_id, EventKind::StageFinished(stage));
+			None
+		}
+		Err((error, attempts)) => {
+			let failure = OrderError { stage, error, attempts };
+			report_failure(*cup_id, &failure);
+			orders.fail(*cup_id, failure.clone());
+			Some(failure)
+		}
+	}
+}
+
+/// The errors for anything a recipe needs that none of the components
+/// measure out, e.g. oat milk on a machine without an oat milk tank.
+fn missing_supplies<'a>(components: &'a [(Component, Measure)], s: Size, r: &Recipe) -> impl Iterator<Item = MachineError> + 'a {
+	r.needs(s).into_iter()
+		.filter(move |need| !components.iter().any(|(_, measure)| measure == need))
+		.map(|need| MachineError::Unavailable { supply: need.to_string() })
+}
+
+/// Runs the readiness checks on the components of the machine an order needs,
+/// in order. Each component is paired with the Measure of how much of its job
+/// the recipe needs, and components the recipe doesn't need are skipped.
+/// Containers also have their material checked against that amount. Anything
+/// the recipe needs that no component measures out fails as Unavailable.
+/// Each component is pinged with its own timeout and under its own
+/// RetryPolicy, and a check that passes gives the name of the component and
+/// the attempts its ping took.
+fn run_checks(components: &[(Component, Measure)], timeouts: &Timeouts, s: Size, r: &Recipe, retries: &RetryPolicies) -> Vec<Result<(&'static str, usize), MachineError>> {
+	let checks = components.iter()
+		.filter(|(_, measure)| measure.of(r, s) > 0.0)
+		.map(|(c, measure)| {
+			let (t_o, retry) = (timeouts.get(c.name(), None), retries.get(c.name()));
+			match c {
+				Component::Device(d) => check_machine!(d => (t_o) retry retry),
+				Component::Container(c) => check_machine!(c => (t_o, measure.of(r, s)) retry retry),
+			}
+			.map(|attempts| (c.name(), attempts))
+		});
+	missing_supplies(components, s, r).map(Err).chain(checks).collect()
+}
+
+/// Checks that the machine holds enough of everything a recipe needs for a
+/// size of cup, without pinging any of the components.
+pub fn check_inventory(components: &[(Component, Measure)], s: Size, r: &Recipe) -> Result<(), MachineError> {
+	if let Some(e) = missing_supplies(components, s, r).next() {
+		return Err(e);
+	}
+	for (c, measure) in components {
+		if let Component::Container(c) = c {
+			let required = measure.of(r, s);
+			if required > 0.0 {
+				c.check_capacity(required)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Why an order couldn't be made: the stage of the machine that failed, the
+/// error from its component and how many attempts were made at the job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderError {
+	pub stage: &'static str,
+	pub error: MachineError,
+	pub attempts: usize,
+}
+impl OrderError {
+	// A stage that couldn't pass an order on to the next.
+	fn disconnected(stage: &'static str) -> Self {
+		OrderError { stage, error: MachineError::Disconnected { stage }, attempts: 1 }
+	}
+}
+impl fmt::Display for OrderError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.attempts > 1 {
+			write!(f, "{} failed after {} attempts: {}", self.stage, self.attempts, self.error)
+		} else {
+			write!(f, "{} failed: {}", self.stage, self.error)
+		}
+	}
+}
+impl std::error::Error for OrderError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(&self.error)
+	}
+}
+
+/// How far along an order submitted to a Machine is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderStatus {
+	/// The order is still being made, on however many branches of the
+	/// machine haven't finished yet.
+	Making { branches_left: usize },
+	/// Every branch of the order is finished, and its cup is being put
+	/// together.
+	Assembling,
+	/// The order's cup is finished.
+	Ready,
+	/// A stage of the machine failed while making the order.
+	Failed(OrderError),
+}
+impl OrderStatus {
+	/// Whether the order is done with, i.e. ready or failed.
+	pub fn is_finished(&self) -> bool {
+		matches!(self, OrderStatus::Ready | OrderStatus::Failed(_))
+	}
+}
+impl fmt::Display for OrderStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			OrderStatus::Making { branches_left } => write!(f, "Making, {} branches left", branches_left),
+			OrderStatus::Assembling => write!(f, "Assembling"),
+			OrderStatus::Ready => write!(f, "Ready"),
+			OrderStatus::Failed(e) => write!(f, "Failed, {}", e),
+		}
+	}
+}
+
+/// When an order was started and when it finished, if it has, on the clock
+/// of the machine's Simulation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrderTiming {
+	pub started: Duration,
+	pub finished: Option<Duration>,
+}
+impl OrderTiming {
+	/// How long the order took from being started to being ready or failing.
+	pub fn took(&self) -> Option<Duration> {
+		self.finished.map(|finished| finished.saturating_sub(self.started))
+	}
+}
+
+// An order's cup, how far along it is and when, the material drawn for it so
+// far, the wakers of any handles waiting on it and whether its OrderHandle is
+// still held.
+struct Progress {
+	cup: Cup,
+	status: OrderStatus,
+	timing: OrderTiming,
+	drawn: Vec<(Arc<dyn Container + Send + Sync>, f32)>,
+	wakers: Vec<Waker>,
+	held: bool,
+}
+
+/// The most finished orders a Machine keeps, so that a machine taking orders
+/// all day doesn't run out of memory. Once more orders than this are finished
+/// the oldest are forgotten, except any whose OrderHandle is still held.
+pub const RETAINED_ORDERS: usize = 1000;
+
+/// Puts material drawn for a failed order back in its container. A container
+/// refilled since the material was drawn is only filled to its capacity.
+fn put_back(cup_id: usize, container: &(dyn Container + Send + Sync), amount: f32) {
+	if container.top_up(amount).is_err() {
+		container.refill();
+	}
+	logging::info(format!("Returned {} to {} for Client {}", container.unit().amount(amount), container.name(), cup_id))
+		.with("cup_id", cup_id)
+		.with("component", container.name())
+		.with("amount", amount)
+		.log();
+}
+
+/// The orders submitted to a machine and how far along each one is. Shared
+/// between the machine, its order handles and its pipeline stages, which
+/// report back as each branch of an order finishes. Orders are timed on the
+/// clock of the stream of the machine's Simulation that starts or finishes
+/// them, or on the Simulation's own clock outside of one. Every change to an
+/// order is published as an Event, and only the last RETAINED_ORDERS finished
+/// orders are kept.
+struct Orders {
+	progress: Mutex<HashMap<usize, Progress>>,
+	// the finished orders, oldest first. Only locked with progress locked.
+	finished: Mutex<VecDeque<usize>>,
+	changed: Condvar,
+	clock: Arc<dyn Clock>,
+	events: EventBus,
+}
+impl Orders {
+	fn new(clock: Arc<dyn Clock>) -> Self {
+		Orders {
+			progress: Mutex::new(HashMap::new()),
+			finished: Mutex::new(VecDeque::new()),
+			changed: Condvar::new(),
+			clock,
+			events: EventBus::new(),
+		}
+	}
+	fn now(&self) -> Duration {
+		simulation::now().unwrap_or_else(|| self.clock.now())
+	}
+	// Publishes an event for an order. Events are published while the orders
+	// are locked, so that subscribers get them in the order they happened.
+	fn publish(&self, cup_id: usize, cup: &Cup, kind: EventKind) {
+		self.events.publish(Event {
+			cup_id,
+			customer: cup.client.clone(),
+			drink: cup.recipe.name().to_string(),
+			kind,
+			at: self.now(),
+		});
+	}
+	fn start(&self, cup_id: usize, cup: Cup, branches: usize) {
+		let status = if branches == 0 { OrderStatus::Assembling } else { OrderStatus::Making { branches_left: branches } };
+		let timing = OrderTiming { started: self.now(), finished: None };
+		let mut progress = self.progress.lock().unwrap();
+		self.publish(cup_id, &cup, EventKind::Accepted);
+		if branches == 0 {
+			self.publish(cup_id, &cup, EventKind::StageStarted("assemble_cups"));
+		}
+		progress.insert(cup_id, Progress { cup, status, timing, drawn: Vec::new(), wakers: Vec::new(), held: true });
+		self.changed.notify_all();
+	}
+	// Notes that a stage started or finished its part of an order that hasn't
+	// failed.
+	fn stage(&self, cup_id: usize, kind: EventKind) {
+		if let Some(p) = self.progress.lock().unwrap().get(&cup_id) {
+			if !p.status.is_finished() {
+				self.publish(cup_id, &p.cup, kind);
+			}
+		}
+	}
+	// Moves an order that isn't finished on to its next status, and wakes
+	// anything waiting on it once it is finished.
+	fn update(&self, cup_id: usize, next: impl FnOnce(&mut Progress)) {
+		let mut progress = self.progress.lock().unwrap();
+		if let Some(p) = progress.get_mut(&cup_id) {
+			if !p.status.is_finished() {
+				let was_assembling = p.status == OrderStatus::Assembling;
+				next(p);
+				match &p.status {
+					OrderStatus::Assembling if !was_assembling => self.publish(cup_id, &p.cup, EventKind::StageStarted("assemble_cups")),
+					OrderStatus::Ready => self.publish(cup_id, &p.cup, EventKind::Ready),
+					OrderStatus::Failed(e) => self.publish(cup_id, &p.cup, EventKind::StageFailed(e.clone())),
+					_ => (),
+				}
+				if p.status.is_finished() {
+					let status = if p.status == OrderStatus::Ready { "ready" } else { "failed" };
+					metrics::global().order_finished(p.cup.recipe.name(), p.cup.size.ounces(), status);
+					p.timing.finished = Some(self.now());
+					p.wakers.drain(..).for_each(Waker::wake);
+					self.finished.lock().unwrap().push_back(cup_id);
+					self.forget_oldest(&mut progress);
+				}
+			}
+		}
+		self.changed.notify_all();
+	}
+	// Forgets the oldest finished orders beyond RETAINED_ORDERS whose handles
+	// have been dropped.
+	fn forget_oldest(&self, progress: &mut HashMap<usize, Progress>) {
+		let mut finished = self.finished.lock().unwrap();
+		let mut excess = finished.len().saturating_sub(RETAINED_ORDERS);
+		finished.retain(|id| {
+			let forget = excess > 0 && !progress[id].held;
+			if forget {
+				progress.remove(id);
+				excess -= 1;
+			}
+			!forget
+		});
+	}
+	// Notes that an order's handle has been dropped, so that the order can be
+	// forgotten once it is one of the oldest finished.
+	fn release(&self, cup_id: usize) {
+		let mut progress = self.progress.lock().unwrap();
+		if let Some(p) = progress.get_mut(&cup_id) {
+			p.held = false;
+			if p.status.is_finished() {
+				self.forget_oldest(&mut progress);
+			}
+		}
+	}
+	fn branch_done(&self, cup_id: usize) {
+		self.update(cup_id, |p| if let OrderStatus::Making { branches_left } = p.status {
+			p.status = if branches_left > 1 {
+				OrderStatus::Making { branches_left: branches_left - 1 }
 			} else {
-				println!("Unknown Error starting thread: {:?}", e);
+				OrderStatus::Assembling
+			};
+		});
+	}
+	fn ready(&self, cup_id: usize, cup: Cup) {
+		self.update(cup_id, |p| {
+			let attempts = std::mem::take(&mut p.cup.attempts);
+			p.cup = Cup { attempts, ..cup };
+			p.status = OrderStatus::Ready;
+			p.drawn.clear();
+		});
+	}
+	// Notes a component that took more than one attempt at its job for an
+	// order.
+	fn retried(&self, cup_id: usize, component: &'static str, attempts: usize) {
+		if let Some(p) = self.progress.lock().unwrap().get_mut(&cup_id) {
+			p.cup.attempts.push((component, attempts));
+		}
+	}
+	// The first failure of an order is kept, and everything drawn for it so
+	// far is put back. The order's other branches still report in, but it
+	// stays failed.
+	fn fail(&self, cup_id: usize, failure: OrderError) {
+		self.update(cup_id, |p| {
+			p.status = OrderStatus::Failed(failure);
+			for (container, amount) in p.drawn.drain(..) {
+				put_back(cup_id, container.as_ref(), amount);
 			}
-			return;
-		}
-	}
-
-	// for each cup of coffee weneed to make, run the checks on the machine
-	// components necessary to know if the cup of coffee can be made or not.
-	// if the checks pass, start making the coffee. If not, print error.
-	for (id, cup) in cups.iter().enumerate() {
-		let mut passed = true;
-		for check in run_checks(TIMEOUT, cup.size) {
-			match check {
-				Err(e) => {
-					passed = false;
-					println!("{}", e);
-				},
-				_ => (),
+		});
+	}
+	fn failure(&self, cup_id: usize) -> Option<OrderError> {
+		match self.progress.lock().unwrap().get(&cup_id) {
+			Some(Progress { status: OrderStatus::Failed(failure), .. }) => Some(failure.clone()),
+			_ => None,
+		}
+	}
+	// Notes material drawn for an order, so that it can be put back if the
+	// order fails. Material drawn for an order that has already failed, e.g.
+	// by a sibling branch that was part way through a stage, is put straight
+	// back.
+	fn drawn(&self, cup_id: usize, container: &Arc<dyn Container + Send + Sync>, amount: f32) {
+		let mut progress = self.progress.lock().unwrap();
+		match progress.get_mut(&cup_id) {
+			Some(p) if !p.status.is_finished() => p.drawn.push((Arc::clone(container), amount)),
+			_ => put_back(cup_id, container.as_ref(), amount),
+		}
+	}
+	fn status(&self, cup_id: usize) -> Option<OrderStatus> {
+		self.progress.lock().unwrap().get(&cup_id).map(|p| p.status.clone())
+	}
+	fn timing(&self, cup_id: usize) -> Option<OrderTiming> {
+		self.progress.lock().unwrap().get(&cup_id).map(|p| p.timing)
+	}
+	fn cup(&self, cup_id: usize) -> Option<Cup> {
+		self.progress.lock().unwrap().get(&cup_id).map(|p| p.cup.clone())
+	}
+	// Blocks until the order is no longer being made.
+	fn wait(&self, cup_id: usize) -> Option<OrderStatus> {
+		let mut progress = self.progress.lock().unwrap();
+		loop {
+			match progress.get(&cup_id) {
+				Some(p) if !p.status.is_finished() => progress = self.changed.wait(progress).unwrap(),
+				other => return other.map(|p| p.status.clone()),
 			}
 		}
-		if passed {
-			start_coffee_maker(&grind_send, &milk_send, TIMEOUT, id, cup.size);
+	}
+	// Blocks until none of the orders are being made.
+	fn wait_all(&self) {
+		let mut progress = self.progress.lock().unwrap();
+		while progress.values().any(|p| !p.status.is_finished()) {
+			progress = self.changed.wait(progress).unwrap();
+		}
+	}
+}
+
+/// A handle on an order submitted to a Machine. Awaiting it gives the
+/// finished Cup once the order is ready, or the stage that failed to make it.
+/// The machine keeps the order for as long as its handle is held.
+pub struct OrderHandle {
+	id: usize,
+	orders: Arc<Orders>,
+}
+impl OrderHandle {
+	pub fn id(&self) -> usize {
+		self.id
+	}
+	pub fn status(&self) -> OrderStatus {
+		// a handle is only made for an order that has been started.
+		self.orders.status(self.id).unwrap()
+	}
+}
+impl Future for OrderHandle {
+	type Output = Result<Cup, OrderError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let mut progress = self.orders.progress.lock().unwrap();
+		let p = progress.get_mut(&self.id).unwrap();
+		match &p.status {
+			OrderStatus::Ready => Poll::Ready(Ok(p.cup.clone())),
+			OrderStatus::Failed(e) => Poll::Ready(Err(e.clone())),
+			_ => {
+				p.wakers.push(cx.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+impl Drop for OrderHandle {
+	fn drop(&mut self) {
+		self.orders.release(self.id);
+	}
+}
+
+/// The branches of the machine an order is made on, each putting its own
+/// part of the recipe in the cup.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Branch {
+	Espresso,
+	Milk,
+	HotWater,
+	Syrup,
+}
+impl Branch {
+	// Whether a step of a recipe is made on this branch.
+	fn makes(&self, step: &Step) -> bool {
+		matches!(
+			(self, step),
+			(Branch::Espresso, Step::Espresso(_))
+				| (Branch::Milk, Step::SteamedMilk(_) | Step::Foam(_))
+				| (Branch::HotWater, Step::HotWater(_))
+				| (Branch::Syrup, Step::Syrup(..))
+		)
+	}
+}
+
+/// What the assemble_cups stage is sent: the cup for an order along with how
+/// many branches will pour into it, then each branch as it finishes, with
+/// why it failed if it did. Each is sent along with when it was sent, as a
+/// ChannelData is.
+enum Assembly {
+	Cup(usize, Cup, usize, Duration),
+	Branch(usize, Branch, Option<OrderError>, Duration),
+}
+
+// The time on the clock of the stream of the simulation the thread is running
+// in. Every stage runs in a stream of its own, and orders are started in the
+// stream they are checked in.
+fn now() -> Duration {
+	simulation::now().unwrap_or_default()
+}
+
+/// Tells the assemble_cups stage that an order's branch is finished, or why
+/// it failed. The order fails if the stage has shut down.
+fn end_branch(assemble_send: &S<Assembly>, orders: &Orders, cup_id: usize, branch: Branch, stage: &'static str, failure: Option<OrderError>) {
+	if assemble_send.send(Assembly::Branch(cup_id, branch, failure, now())).is_err() {
+		let failure = OrderError::disconnected(stage);
+		report_failure(cup_id, &failure);
+		orders.fail(cup_id, failure);
+	}
+}
+
+/// Puts together the cup of every order as its branches finish. The
+/// branches that are in are noted, and once every branch of an order is in,
+/// the ingredients each made for the size of cup are added in the order of
+/// the recipe's steps, whatever order the branches finished in. The cup is
+/// then finished and the order is ready. The cup of an order is thrown away
+/// as soon as any of its branches fails. Like the other stages, it takes its
+/// cups and branches from an iterator and gives how many it took.
+fn assemble_cups(cups: &mut Assembling, assemble_recv: impl Iterator<Item = Assembly>, orders: &Orders) -> usize {
+	let mut taken = 0;
+	for assembly in assemble_recv {
+		taken += 1;
+		let cup_id = match assembly {
+			Assembly::Cup(cup_id, cup, branches, at) => {
+				simulation::wait_until(at);
+				cups.insert(cup_id, (cup, branches, Vec::new()));
+				cup_id
+			}
+			Assembly::Branch(cup_id, _, Some(failure), at) => {
+				simulation::wait_until(at);
+				// any branch of the order still to come in finds no cup, and
+				// is ignored.
+				cups.remove(&cup_id);
+				orders.fail(cup_id, failure);
+				cup_id
+			}
+			Assembly::Branch(cup_id, branch, None, at) => {
+				simulation::wait_until(at);
+				orders.branch_done(cup_id);
+				if let Some((_, branches_left, done)) = cups.get_mut(&cup_id) {
+					*branches_left = branches_left.saturating_sub(1);
+					done.push(branch);
+				}
+				cup_id
+			}
+		};
+		if let Some((_, 0, _)) = cups.get(&cup_id) {
+			let (cup, _, done) = cups.remove(&cup_id).unwrap();
+			let (recipe, size) = (Arc::clone(&cup.recipe), cup.size);
+			let cup = recipe.steps()
+				.filter(|step| done.iter().any(|b| b.makes(step)) && step.portion().of(size) > 0.0)
+				.fold(cup, |cup, step| cup + step.ingredient(recipe.milk_kind()));
+			logging::info(format!("Coffee Assembled for Client {}!", cup_id))
+				.with("cup_id", cup_id)
+				.with("stage", "assemble_cups")
+				.log();
+			orders.ready(cup_id, cup);
+		}
+	}
+	taken
+}
+
+// The cups the assemble_cups stage is putting together, each with how many of
+// its branches are still to come in and the branches that are in.
+type Assembling = HashMap<usize, (Cup, usize, Vec<Branch>)>;
+
+/// The senders at the start of each branch of the machine, and the sender
+/// that places each order's cup at the assemble_cups stage.
+struct Branches {
+	hopper: S<ChannelData>,
+	milk: S<ChannelData>,
+	pour: S<ChannelData>,
+	syrup: S<ChannelData>,
+	assemble: S<Assembly>,
+}
+
+/// Starts an order on each branch of the machine its recipe needs: the
+/// espresso branch at the coffee hopper, the milk branch at the milk tanks,
+/// hot water at the water tank and syrup at the syrup pumps. Its cup is
+/// placed at the assemble_cups stage to be filled as the branches finish.
+fn start_coffee_maker(branches: &Branches, orders: &Orders, client_id: usize, cup: Cup) {
+	let (size, recipe) = (cup.size, Arc::clone(&cup.recipe));
+	let assemble = &branches.assemble;
+	let branches = [
+		(&branches.hopper, Branch::Espresso, recipe.shots(size), "Coffee Beans"),
+		(&branches.milk, Branch::Milk, recipe.milk(size), "Milk"),
+		(&branches.pour, Branch::HotWater, recipe.hot_water(size), "Hot Water"),
+		(&branches.syrup, Branch::Syrup, recipe.syrups(size), "Syrup"),
+	];
+	let needed: Vec<_> = branches.iter().filter(|(_, _, amount, _)| *amount > 0.0).collect();
+	let disconnected = || OrderError::disconnected("start_coffee_maker");
+	// the order is tracked and its cup placed before any branch starts, so
+	// that a branch can't finish before the machine knows to wait for it.
+	orders.start(client_id, cup.clone(), needed.len());
+	let started = now();
+	if assemble.send(Assembly::Cup(client_id, cup, needed.len(), started)).is_err() {
+		orders.fail(client_id, disconnected());
+		return;
+	}
+	for (send, branch, _, what) in needed {
+		match send.send((client_id, Option::Some(size), Arc::clone(&recipe), None, started)) {
+			Ok(()) => logging::info(format!("Client {} {} Started!", client_id, what))
+				.with("cup_id", client_id)
+				.with("branch", *what)
+				.log(),
+			Err(e) => {
+				logging::error(format!("Error Starting Client {} {}! {}", client_id, what, e))
+					.with("cup_id", client_id)
+					.with("branch", *what)
+					.with("error_kind", "disconnected")
+					.log();
+				orders.fail(client_id, disconnected());
+				end_branch(assemble, orders, client_id, *branch, "start_coffee_maker", Some(disconnected()));
+			}
+		}
+	}
+}
+
+create_pipeline!(grind_coffee(hopper_recv, water_send) => Branch::Espresso { "Coffee Ground for Client {}!" });
+create_pipeline!(dispense_water(water_recv, press_send) => Branch::Espresso { "Water Dispensed for Client {}!" });
+create_pipeline!(press_espresso(press_recv) => Branch::Espresso { "Espresso Pressed for Client {}!" });
+create_pipeline!(heat_milk(milk_recv, froth_send if Measure::Foam) => Branch::Milk { "Milk heated for Client {}!" });
+create_pipeline!(froth_milk(froth_recv) => Branch::Milk { "Milk frothed for Client {}!" });
+create_pipeline!(pour_water(pour_recv) => Branch::HotWater { "Hot Water Poured for Client {}!" });
+create_pipeline!(pump_syrup(syrup_recv) => Branch::Syrup { "Syrup Pumped for Client {}!" });
+
+/// A stage of the machine, ready to run. Given true it blocks on its channel
+/// until the channel is closed, as it does on a thread of its own. Given false
+/// it only takes the orders waiting on it, as it does when stepped. Either way
+/// it gives how many it took.
+type Stage = Box<dyn FnMut(bool) -> usize + Send>;
+
+/// This macro contains one definition that makes a Stage from a call to one
+/// of the stage functions. It requires the receiver the stage takes from,
+/// followed by `=>` and a closure-like call with the name the iterator over
+/// the receiver is bound to, e.g.
+/// `grind_recv => |data| grind_coffee(&grind, data, ...)`. Everything the call
+/// uses is moved into the Stage.
+macro_rules! stage {
+	($recv_name: ident => |$data: ident| $call: expr) => {
+		Box::new(move |block: bool| if block {
+			let $data = $recv_name.iter();
+			$call
 		} else {
-			println!("Cannot make {}'s Coffee!", cup.client);
+			let $data = $recv_name.try_iter();
+			$call
+		}) as Stage
+	};
+}
+
+/// The stages of a seeded machine, each in its stream of the simulation.
+/// Rather than running on threads of their own, they are stepped in a fixed
+/// order on the thread an order is submitted from, so that a run with the
+/// same seed always goes the same way.
+struct Stepper {
+	stages: Vec<(Stream, Stage)>,
+}
+impl Stepper {
+	// Steps every stage in turn, each taking the orders waiting on it, until
+	// none of them has anything left to take.
+	fn run(&mut self) {
+		loop {
+			let taken: usize = self.stages.iter_mut()
+				.map(|(stream, stage)| stream.enter(|| stage(false)))
+				.sum();
+			if taken == 0 {
+				break;
+			}
 		}
 	}
-	drop(grind_send);
-	drop(milk_send);
-	wg.wait().await;
 }
 
-pub fn message_based_main() {
-	// do stuff
-	block_on(do_five_times());
-}
\ No newline at end of file
+/// How a Machine runs: the timeout and RetryPolicy of each of its components
+/// and the Simulation it gets its time and randomness from.
+#[derive(Clone, Default)]
+pub struct Settings {
+	pub timeouts: Timeouts,
+	pub retries: RetryPolicies,
+	pub simulation: Simulation,
+}
+
+/// An espresso machine, with each stage of its pipelines running on a thread
+/// of its own until the machine is shut down. When its Simulation is seeded,
+/// the stages are instead stepped in a fixed order as each order is
+/// submitted, so that the order is finished by the time `submit` returns and
+/// a run with the same seed always gives the same cups.<br>
+/// Orders are submitted as Cups, e.g.
+/// `machine.submit(Cup::order(&menu, "Latte", Size::MEDIUM, name))`, and each
+/// gets an OrderHandle that can be awaited for the finished cup. Its id can
+/// also be used to look up or wait on its status from the machine.
+pub struct Machine {
+	components: Vec<(Component, Measure)>,
+	branches: Option<Branches>,
+	orders: Arc<Orders>,
+	next_id: AtomicUsize,
+	// shared with the stages so that they can be changed while it runs.
+	timeouts: Arc<RwLock<Timeouts>>,
+	settings: Settings,
+	// the stream of the simulation that orders are checked in.
+	checks: Stream,
+	// the threads the stages run on, or the stages themselves when the
+	// simulation is seeded.
+	threads: Vec<thread::JoinHandle<()>>,
+	stepper: Option<Mutex<Stepper>>,
+	wg: WaitGroup,
+}
+
+impl Default for Machine {
+	fn default() -> Self {
+		Machine::new()
+	}
+}
+
+impl Machine {
+	/// Starts a machine with the standard components.
+	pub fn new() -> Self {
+		Machine::start(Machine::standard_components())
+	}
+
+	/// The components of a standard machine, each paired with the Measure of
+	/// how much of its job a recipe needs. The hopper, tanks and syrup
+	/// bottles start full, and there is a milk tank for every kind of milk and
+	/// a pump for every flavour of syrup.
+	pub fn standard_components() -> Vec<(Component, Measure)> {
+		MachineConfig::standard().components()
+	}
+
+	/// Starts a machine from a list of components, each paired with the
+	/// Measure of how much of its job a recipe needs. Every component is put
+	/// in the pipeline stage that draws on its Measure, e.g. a component
+	/// measuring Water supplies both the espresso and the hot water branches.
+	/// The machine runs with the default Settings.
+	pub fn start(components: Vec<(Component, Measure)>) -> Self {
+		Machine::start_with(components, Settings::default())
+	}
+
+	/// Starts a machine from a list of components like `start`, with the
+	/// given Settings.
+	pub fn start_with(components: Vec<(Component, Measure)>, settings: Settings) -> Self {
+		let mut grind: Jobs = Vec::new();
+		let mut dispense: Jobs = Vec::new();
+		let mut press: Jobs = Vec::new();
+		let mut heat: Jobs = Vec::new();
+		let mut froth: Jobs = Vec::new();
+		let mut pour: Jobs = Vec::new();
+		let mut pump: Jobs = Vec::new();
+		for (c, measure) in &components {
+			let (job, retry) = (c.clone(), settings.retries.get(c.name()));
+			match measure {
+				Measure::Beans => grind.push((job, *measure, retry)),
+				Measure::EspressoWater => dispense.push((job, *measure, retry)),
+				Measure::Water => {
+					dispense.push((job.clone(), Measure::EspressoWater, retry));
+					pour.push((job, Measure::HotWater, retry));
+				}
+				Measure::Shots => press.push((job, *measure, retry)),
+				Measure::Milk(_) => heat.push((job, *measure, retry)),
+				Measure::Foam => froth.push((job, *measure, retry)),
+				Measure::HotWater => pour.push((job, *measure, retry)),
+				Measure::Syrup(_) => pump.push((job, *measure, retry)),
+			}
+		}
+		// create a workgroup that will generate workers that will be passed to
+		// threads. The workers are dropped as the threads finish, so shutting
+		// down can wait on every stage to drain.
+		let wg = WaitGroup::new();
+		let orders = Arc::new(Orders::new(Arc::clone(settings.simulation.clock())));
+		let timeouts = Arc::new(RwLock::new(settings.timeouts.clone()));
+		// create a set of channels that will be passing data from thread to thread
+		create_channel!(grind_send, grind_recv);
+		create_channel!(water_send, water_recv);
+		create_channel!(press_send, press_recv);
+		create_channel!(milk_send, milk_recv);
+		create_channel!(froth_send, froth_recv);
+		create_channel!(pour_send, pour_recv);
+		create_channel!(syrup_send, syrup_recv);
+		let (assemble_send, assemble_recv) = mpsc::channel::<Assembly>();
+		// each stage runs in a stream of the simulation of its own.
+		let stages = {
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let grind = stage!(grind_recv => |data| grind_coffee(&grind, data, &water_send, &a, &o, &t));
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let dispense = stage!(water_recv => |data| dispense_water(&dispense, data, &press_send, &a, &o, &t));
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let press = stage!(press_recv => |data| press_espresso(&press, data, &a, &o, &t));
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let heat = stage!(milk_recv => |data| heat_milk(&heat, data, &froth_send, &a, &o, &t));
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let froth = stage!(froth_recv => |data| froth_milk(&froth, data, &a, &o, &t));
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let pour = stage!(pour_recv => |data| pour_water(&pour, data, &a, &o, &t));
+			let (a, o, t) = (assemble_send.clone(), orders.clone(), timeouts.clone());
+			let pump = stage!(syrup_recv => |data| pump_syrup(&pump, data, &a, &o, &t));
+			let (o, mut cups) = (orders.clone(), Assembling::new());
+			let assemble = stage!(assemble_recv => |data| assemble_cups(&mut cups, data, &o));
+			vec![
+				("grind_coffee", grind),
+				("dispense_water", dispense),
+				("press_espresso", press),
+				("heat_milk", heat),
+				("froth_milk", froth),
+				("pour_water", pour),
+				("pump_syrup", pump),
+				("assemble_cups", assemble),
+			]
+		};
+		let stages = stages.into_iter().map(|(name, stage)| (settings.simulation.stream(name), stage));
+		// a seeded machine steps its stages in the order above. Otherwise the
+		// threads run until the channels feeding them are closed, and the
+		// assemble_cups stage runs until every other stage has finished.
+		let (threads, stepper) = if settings.simulation.seed().is_some() {
+			(Vec::new(), Some(Mutex::new(Stepper { stages: stages.collect() })))
+		} else {
+			let threads = stages
+				.map(|(stream, mut stage)| {
+					let worker = wg.worker();
+					thread::spawn(move || {
+						stream.enter(|| stage(true));
+						drop(worker);
+					})
+				})
+				.collect();
+			(threads, None)
+		};
+		Machine {
+			components,
+			branches: Some(Branches {
+				hopper: grind_send,
+				milk: milk_send,
+				pour: pour_send,
+				syrup: syrup_send,
+				assemble: assemble_send,
+			}),
+			orders,
+			next_id: AtomicUsize::new(0),
+			timeouts,
+			checks: settings.simulation.shared_stream("checks"),
+			settings,
+			threads,
+			stepper,
+			wg,
+		}
+	}
+
+	/// The Settings the machine runs with, including any timeouts changed
+	/// since it started.
+	pub fn settings(&self) -> Settings {
+		Settings { timeouts: self.timeouts(), ..self.settings.clone() }
+	}
+
+	pub fn timeouts(&self) -> Timeouts {
+		self.timeouts.read().unwrap().clone()
+	}
+
+	/// Changes the timeouts of the running machine. Orders already at a stage
+	/// finish it with the timeouts they started it with.
+	pub fn set_timeouts(&self, timeouts: Timeouts) {
+		*self.timeouts.write().unwrap() = timeouts;
+	}
+
+	/// The components of the machine, each paired with the Measure of how
+	/// much of its job a recipe needs.
+	pub fn components(&self) -> &[(Component, Measure)] {
+		&self.components
+	}
+
+	/// Runs the checks on the components an order needs and, if they pass,
+	/// starts making it. Returns a handle on the order, or the first check
+	/// that failed. Fails as Disconnected once the machine is shut down.
+	pub fn submit(&self, mut cup: Cup) -> Result<OrderHandle, MachineError> {
+		let branches = self.branches.as_ref().ok_or(MachineError::Disconnected { stage: "submit" })?;
+		// a seeded machine takes one order at a time, from its checks until it
+		// is made.
+		let stepper = self.stepper.as_ref().map(|s| s.lock().unwrap());
+		let timeouts = self.timeouts();
+		let checks = self.checks.enter(|| run_checks(&self.components, &timeouts, cup.size, &cup.recipe, &self.settings.retries));
+		let checks = checks.into_iter().collect::<Result<Vec<_>, _>>().inspect_err(|e| {
+			metrics::global().failed(e.component().unwrap_or("submit"), e.kind());
+			let mut record = logging::warn(format!("Cannot make {}'s Coffee! {}", cup.client, e))
+				.with("drink", cup.recipe.name().to_string())
+				.with("error_kind", e.kind());
+			if let Some(component) = e.component() {
+				record = record.with("component", component);
+			}
+			record.log();
+		})?;
+		cup.attempts = checks.into_iter().filter(|(_, attempts)| *attempts > 1).collect();
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		// the order starts at the time it was checked.
+		self.checks.enter(|| start_coffee_maker(branches, &self.orders, id, cup));
+		if let Some(mut stepper) = stepper {
+			stepper.run();
+		}
+		Ok(OrderHandle { id, orders: Arc::clone(&self.orders) })
+	}
+
+	/// How the machines in the process have been running, along with this
+	/// machine's inventory, in the Prometheus text format.
+	pub fn metrics(&self) -> String {
+		metrics::global().render(self)
+	}
+
+	/// Subscribes to the events of every order from now on: each order being
+	/// accepted, each stage starting, finishing or failing on it, and it being
+	/// ready.
+	pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+		self.orders.events.subscribe()
+	}
+
+	/// The status of an order, or None if no order has that id or it has been
+	/// forgotten.
+	pub fn status(&self, id: usize) -> Option<OrderStatus> {
+		self.orders.status(id)
+	}
+
+	/// When an order was started and finished, or None if no order has that
+	/// id or it has been forgotten.
+	pub fn timing(&self, id: usize) -> Option<OrderTiming> {
+		self.orders.timing(id)
+	}
+
+	/// The cup of an order, with what has been put in it so far, or None if no
+	/// order has that id or it has been forgotten.
+	pub fn cup(&self, id: usize) -> Option<Cup> {
+		self.orders.cup(id)
+	}
+
+	/// Every order submitted to the machine that is still being made or is
+	/// one of the last RETAINED_ORDERS finished, in the order they were
+	/// submitted.
+	pub fn orders(&self) -> Vec<(usize, Cup, OrderStatus)> {
+		let mut orders: Vec<_> = self.orders.progress.lock().unwrap().iter()
+			.map(|(id, p)| (*id, p.cup.clone(), p.status.clone()))
+			.collect();
+		orders.sort_by_key(|(id, _, _)| *id);
+		orders
+	}
+
+	/// Blocks until an order is ready or has failed, returning its status, or
+	/// None if no order has that id or it has been forgotten.
+	pub fn wait(&self, id: usize) -> Option<OrderStatus> {
+		self.orders.wait(id)
+	}
+
+	/// Blocks until every order submitted so far is ready or has failed.
+	pub fn wait_all(&self) {
+		self.orders.wait_all()
+	}
+
+	/// The name of every container in the machine and how much material it
+	/// holds.
+	pub fn inventory(&self) -> Vec<(&'static str, Amount)> {
+		self.components.iter()
+			.filter_map(|(c, _)| match c {
+				Component::Container(c) => Some((c.name(), c.unit().amount(c.level()))),
+				Component::Device(_) => None,
+			})
+			.collect()
+	}
+
+	/// Fills the container with the given name up to its capacity, returning
+	/// the amount added, or None if the machine has no such container. The
+	/// pipelines keep running during the refill; only the stage that draws
+	/// from the container waits on it.
+	pub fn refill(&self, name: &str) -> Option<Amount> {
+		self.components.iter().find_map(|(c, _)| match c {
+			Component::Container(c) if c.name().eq_ignore_ascii_case(name) => Some(c.unit().amount(c.refill())),
+			_ => None,
+		})
+	}
+
+	/// Pings every component of the machine once with its timeout and no
+	/// retries, giving the name of each component and how long it took to
+	/// answer, or why it didn't.
+	pub fn diagnostics(&self) -> Vec<(&'static str, Result<Duration, MachineError>)> {
+		let timeouts = self.timeouts();
+		let clock = self.settings.simulation.clock();
+		self.checks.enter(|| self.components.iter()
+			.map(|(c, _)| {
+				let start = clock.now();
+				let answer = c.job().ping(timeouts.get(c.name(), None));
+				(c.name(), answer.map(|()| clock.now().saturating_sub(start)))
+			})
+			.collect())
+	}
+
+	/// Stops taking orders and waits for the pipelines to finish the orders
+	/// already started before the threads exit.
+	pub async fn shutdown(mut self) {
+		// once the senders are dropped the pipelines drain and exit.
+		self.branches = None;
+		for t in self.threads {
+			if let Err(e) = t.join() {
+				let message = match e.downcast_ref::<&'static str>() {
+					Some(e) => format!("Error in pipeline thread: {}", e),
+					None => format!("Unknown Error in pipeline thread: {:?}", e),
+				};
+				logging::error(message).log();
+			}
+		}
+		self.wg.wait().await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::executor::block_on;
+
+	// Makes a few orders on the standard machine with a seeded simulation,
+	// giving what became of each order, every event published while they
+	// were made and how long the run took.
+	fn run(seed: u64) -> (Vec<String>, Vec<Event>, Duration) {
+		// the events are enough to go on, without the log.
+		logging::Logger::new().install();
+		let machine = Machine::start_with(Machine::standard_components(), Settings { simulation: Simulation::seeded(seed), ..Settings::default() });
+		let events = machine.subscribe();
+		let menu = Menu::standard();
+		let orders = [
+			("Josh", "Latte", Size::MEDIUM),
+			("Sharon", "Americano", Size::LARGE),
+			("Moobly", "Cappuccino", Size::oz(4.0)),
+			("Tosh", "Flat White", Size::oz(20.0)),
+			("Mary", "Doppio", Size::SMALL),
+		];
+		for (name, drink, size) in orders {
+			let cup = Cup::order(&menu, drink, size, name.to_string()).unwrap();
+			assert!(machine.submit(cup).is_ok());
+		}
+		let outcomes = machine.orders().into_iter()
+			.map(|(id, cup, status)| format!("{} {} {:?}", id, cup, status))
+			.collect();
+		let took = machine.settings().simulation.elapsed();
+		block_on(machine.shutdown());
+		(outcomes, events.try_iter().collect(), took)
+	}
+
+	#[test]
+	fn seeded_run_is_the_same_every_time() {
+		let (outcomes, events, took) = run(4);
+		assert_eq!(outcomes.len(), 5);
+		// seed 4 has the press stall on the cappuccino.
+		assert!(outcomes[2].contains("Timeout"), "{:?}", outcomes);
+		assert!(events.iter().any(|e| e.kind == EventKind::Ready));
+		assert!(took > Duration::ZERO);
+		assert_eq!(run(4), (outcomes, events, took));
+	}
+
+	#[test]
+	fn forgets_the_oldest_finished_orders_once_their_handles_are_dropped() {
+		let orders = Orders::new(Arc::clone(Simulation::seeded(1).clock()));
+		let cup = Cup::order(&Menu::standard(), "Espresso", Size::SMALL, "Josh".to_string()).unwrap();
+		// the handle of the first order is kept, and the rest are dropped.
+		for id in 0..RETAINED_ORDERS + 2 {
+			orders.start(id, cup.clone(), 0);
+			orders.ready(id, cup.clone());
+			if id > 0 {
+				orders.release(id);
+			}
+		}
+		let kept: Vec<bool> = (0..4).map(|id| orders.status(id).is_some()).collect();
+		assert_eq!(kept, vec![true, false, false, true]);
+		assert_eq!(orders.progress.lock().unwrap().len(), RETAINED_ORDERS);
+		orders.release(0);
+		assert_eq!(orders.status(0), Some(OrderStatus::Ready));
+	}
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::message_based::Machine;
+use crate::units::Amount;
+
+// The upper bounds of the buckets every histogram counts durations in, in
+// seconds. They cover a ping of a few milliseconds up to a slow stage.
+const BUCKETS: [f64; 13] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// The name, type and help of every metric, in the order they are written.
+const DESCRIPTIONS: [(&str, &str, &str); 5] = [
+	("espresso_orders_total", "counter", "Orders finished, by drink, size in fluid ounces and whether they were ready or failed."),
+	("espresso_failures_total", "counter", "Orders failed or refused, by the component that failed and the kind of error."),
+	("espresso_stage_duration_seconds", "histogram", "How long each stage of the machine took over an order."),
+	("espresso_ping_duration_seconds", "histogram", "How long each component took to answer a ping, up to its timeout."),
+	("espresso_inventory_ounces", "gauge", "How much each container of the machine holds."),
+];
+
+// The labels of one series of a metric, e.g. [("stage", "heat_milk")].
+type Labels = Vec<(&'static str, String)>;
+
+// A histogram's count in each bucket, its count of observations and their sum.
+#[derive(Default)]
+struct Histogram {
+	buckets: [u64; BUCKETS.len()],
+	count: u64,
+	sum: f64,
+}
+
+/// Counters and histograms of how the machines in the process are running,
+/// written out in the Prometheus text format. Every machine records into the
+/// one `global` Metrics, the way every machine logs through the one Logger.
+pub struct Metrics {
+	counters: Mutex<BTreeMap<(&'static str, Labels), f64>>,
+	histograms: Mutex<BTreeMap<(&'static str, Labels), Histogram>>,
+}
+impl Metrics {
+	pub const fn new() -> Self {
+		Metrics { counters: Mutex::new(BTreeMap::new()), histograms: Mutex::new(BTreeMap::new()) }
+	}
+
+	/// Adds one to the counter of a metric with the given labels.
+	pub fn count(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+		*self.counters.lock().unwrap().entry((name, to_labels(labels))).or_insert(0.0) += 1.0;
+	}
+
+	/// Counts a duration in the histogram of a metric with the given labels.
+	pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], took: Duration) {
+		let seconds = took.as_secs_f64();
+		let mut histograms = self.histograms.lock().unwrap();
+		let histogram = histograms.entry((name, to_labels(labels))).or_default();
+		for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS.iter()) {
+			if seconds <= *bound {
+				*bucket += 1;
+			}
+		}
+		histogram.count += 1;
+		histogram.sum += seconds;
+	}
+
+	pub fn order_finished(&self, drink: &str, ounces: f32, status: &str) {
+		self.count("espresso_orders_total", &[("drink", drink), ("size_oz", &ounces.to_string()), ("status", status)]);
+	}
+
+	pub fn failed(&self, component: &str, error_kind: &str) {
+		self.count("espresso_failures_total", &[("component", component), ("error_kind", error_kind)]);
+	}
+
+	pub fn stage_took(&self, stage: &str, took: Duration) {
+		self.observe("espresso_stage_duration_seconds", &[("stage", stage)], took);
+	}
+
+	pub fn ping_took(&self, component: &str, took: Duration) {
+		self.observe("espresso_ping_duration_seconds", &[("component", component)], took);
+	}
+
+	/// Every counter and histogram in the Prometheus text format, along with
+	/// the inventory of the machine as gauges.
+	pub fn render(&self, machine: &Machine) -> String {
+		let counters = self.counters.lock().unwrap();
+		let histograms = self.histograms.lock().unwrap();
+		let mut text = String::new();
+		for (name, kind, help) in DESCRIPTIONS.iter() {
+			writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+			for ((_, labels), value) in counters.range((*name, Vec::new())..).take_while(|((n, _), _)| n == name) {
+				writeln!(text, "{}{} {}", name, render_labels(labels), value).unwrap();
+			}
+			for ((_, labels), histogram) in histograms.range((*name, Vec::new())..).take_while(|((n, _), _)| n == name) {
+				for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+					let labels = labels.iter().cloned().chain(Some(("le", bound.to_string()))).collect();
+					writeln!(text, "{}_bucket{} {}", name, render_labels(&labels), count).unwrap();
+				}
+				let all = labels.iter().cloned().chain(Some(("le", "+Inf".to_string()))).collect();
+				writeln!(text, "{}_bucket{} {}", name, render_labels(&all), histogram.count).unwrap();
+				writeln!(text, "{}_sum{} {}", name, render_labels(labels), histogram.sum).unwrap();
+				writeln!(text, "{}_count{} {}", name, render_labels(labels), histogram.count).unwrap();
+			}
+			if *name == "espresso_inventory_ounces" {
+				for (container, level) in machine.inventory() {
+					let ounces = match level {
+						Amount::Mass(m) => m.as_ounces(),
+						Amount::Volume(v) => v.as_fluid_ounces(),
+					};
+					writeln!(text, "{}{{container=\"{}\"}} {}", name, container, ounces).unwrap();
+				}
+			}
+		}
+		text
+	}
+}
+impl Default for Metrics {
+	fn default() -> Self {
+		Metrics::new()
+	}
+}
+
+static METRICS: Metrics = Metrics::new();
+
+/// The Metrics every machine in the process records into.
+pub fn global() -> &'static Metrics {
+	&METRICS
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+	labels.iter().map(|(key, value)| (*key, value.to_string())).collect()
+}
+
+// Labels as `{key="value",...}`, escaped as the text format asks, or nothing
+// for a series without labels.
+fn render_labels(labels: &Labels) -> String {
+	if labels.is_empty() {
+		return String::new();
+	}
+	let labels: Vec<String> = labels.iter()
+		.map(|(key, value)| format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+		.collect();
+	format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::message_based::Settings;
+	use crate::simulation::Simulation;
+
+	fn machine() -> Machine {
+		crate::logging::Logger::new().install();
+		Machine::start_with(Machine::standard_components(), Settings { simulation: Simulation::seeded(1), ..Settings::default() })
+	}
+
+	#[test]
+	fn renders_counters_and_histograms_in_the_text_format() {
+		let metrics = Metrics::new();
+		metrics.order_finished("Latte", 12.0, "ready");
+		metrics.order_finished("Latte", 12.0, "ready");
+		metrics.failed("EspressoPress", "timeout");
+		metrics.stage_took("heat_milk", Duration::from_millis(300));
+		let text = metrics.render(&machine());
+		let lines: Vec<&str> = text.lines().collect();
+		for line in [
+			"# TYPE espresso_orders_total counter",
+			"espresso_orders_total{drink=\"Latte\",size_oz=\"12\",status=\"ready\"} 2",
+			"espresso_failures_total{component=\"EspressoPress\",error_kind=\"timeout\"} 1",
+			"# TYPE espresso_stage_duration_seconds histogram",
+			"espresso_stage_duration_seconds_bucket{stage=\"heat_milk\",le=\"0.25\"} 0",
+			"espresso_stage_duration_seconds_bucket{stage=\"heat_milk\",le=\"0.5\"} 1",
+			"espresso_stage_duration_seconds_bucket{stage=\"heat_milk\",le=\"+Inf\"} 1",
+			"espresso_stage_duration_seconds_sum{stage=\"heat_milk\"} 0.3",
+			"espresso_stage_duration_seconds_count{stage=\"heat_milk\"} 1",
+			"# TYPE espresso_ping_duration_seconds histogram",
+			"espresso_inventory_ounces{container=\"WholeMilkTank\"} 64",
+		] {
+			assert!(lines.contains(&line), "no `{}` in\n{}", line, text);
+		}
+		// a metric without series is still described.
+		assert!(!text.contains("espresso_ping_duration_seconds_count"));
+	}
+
+	#[test]
+	fn escapes_label_values() {
+		let labels = vec![("drink", "The \"Usual\"\\\n".to_string())];
+		assert_eq!(render_labels(&labels), "{drink=\"The \\\"Usual\\\"\\\\\\n\"}");
+		assert_eq!(render_labels(&Vec::new()), "");
+	}
+}
@@ -0,0 +1,454 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::machine_components::{Ingredient, Milk, Size, Syrup};
+
+// The ounces of syrup pumped into a small, medium and large cup.
+const SYRUP_PORTION: Portion = Portion::new(0.5, 1.0, 1.5);
+
+/// Something a recipe needs measured out by a component of the machine, e.g.
+/// the beans ground by the coffee hopper or the oat milk drawn from the oat
+/// milk tank. Used to tell each component how much work an order needs from
+/// it, and to find the component that holds what an order needs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Measure {
+	/// Ounces of beans ground.
+	Beans,
+	/// Ounces of water pressed through the grounds.
+	EspressoWater,
+	/// Shots of espresso pressed.
+	Shots,
+	/// Ounces of water poured straight into the cup.
+	HotWater,
+	/// All of the ounces of water, for espresso and hot water.
+	Water,
+	/// Ounces of a kind of milk heated, including milk that will be frothed.
+	Milk(Milk),
+	/// Ounces of milk frothed.
+	Foam,
+	/// Ounces of a flavour syrup pumped.
+	Syrup(Syrup),
+}
+impl Measure {
+	/// How much of this the recipe needs for the size of cup.
+	pub fn of(&self, r: &Recipe, s: Size) -> f32 {
+		match self {
+			Measure::Beans => r.beans(s),
+			Measure::EspressoWater => r.espresso_water(s),
+			Measure::Shots => r.shots(s),
+			Measure::HotWater => r.hot_water(s),
+			Measure::Water => r.water(s),
+			Measure::Milk(kind) if *kind == r.milk_kind() => r.milk(s),
+			Measure::Milk(_) => 0.0,
+			Measure::Foam => r.foam(s),
+			Measure::Syrup(kind) => r.syrup(*kind, s),
+		}
+	}
+}
+impl fmt::Display for Measure {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Measure::Beans => write!(f, "Coffee Beans"),
+			Measure::EspressoWater | Measure::HotWater | Measure::Water => write!(f, "Water"),
+			Measure::Shots => write!(f, "Espresso"),
+			Measure::Milk(kind) => write!(f, "{}", kind),
+			Measure::Foam => write!(f, "Foam"),
+			Measure::Syrup(kind) => write!(f, "{}", kind),
+		}
+	}
+}
+
+/// An amount that depends on the size of the cup, e.g. the ounces of milk
+/// steamed for a latte. It is given for the standard small, medium and large
+/// cups, and the amount for any other size is drawn on the line through
+/// those, e.g. a latte with 9 oz. of milk in a 12 oz. cup and 12 oz. in a 16
+/// oz. cup has 15 oz. in a 20 oz. cup. Cups smaller than a small never get
+/// less than the small amount scaled down to their size, and cups larger than
+/// a large never get less than the large amount, so every step of a drink is
+/// still there in a 4 oz. kids cup.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Portion {
+	pub small: f32,
+	pub medium: f32,
+	pub large: f32,
+}
+impl Portion {
+	pub const fn new(small: f32, medium: f32, large: f32) -> Self {
+		Portion { small, medium, large }
+	}
+	/// The same amount for every size of cup.
+	pub const fn each(amount: f32) -> Self {
+		Portion::new(amount, amount, amount)
+	}
+	pub fn of(&self, s: Size) -> f32 {
+		let oz = s.ounces().max(0.0);
+		let small = (Size::SMALL.ounces(), self.small);
+		let medium = (Size::MEDIUM.ounces(), self.medium);
+		let large = (Size::LARGE.ounces(), self.large);
+		// sizes below a medium are measured on the line from small to medium,
+		// and everything else on the line from medium to large.
+		let ((x0, y0), (x1, y1)) = if oz < medium.0 { (small, medium) } else { (medium, large) };
+		let amount = y0 + (y1 - y0) * (oz - x0) / (x1 - x0);
+		if oz < small.0 {
+			amount.max(self.small * oz / small.0)
+		} else if oz > large.0 {
+			amount.max(self.large)
+		} else {
+			amount
+		}
+	}
+}
+
+/// How much a machine grinds and presses for a single shot of espresso, in
+/// ounces of beans and fluid ounces of water. Machines differ in their dose,
+/// so a menu can be set to the dose of the machine it is ordered from with
+/// `Menu::with_dose`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dose {
+	pub beans_per_shot: f32,
+	pub water_per_shot: f32,
+}
+impl Default for Dose {
+	fn default() -> Self {
+		Dose::STANDARD
+	}
+}
+impl Dose {
+	/// An ounce of beans and an ounce of water for every shot.
+	pub const STANDARD: Dose = Dose { beans_per_shot: 1.0, water_per_shot: 1.0 };
+}
+
+/// One thing the machine does to make a drink.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Step {
+	/// Shots of espresso: beans are ground, water dispensed and the espresso
+	/// pressed.
+	Espresso(Portion),
+	/// Ounces of milk heated in the milk tank.
+	SteamedMilk(Portion),
+	/// Ounces of milk heated and then frothed.
+	Foam(Portion),
+	/// Ounces of hot water poured straight into the cup.
+	HotWater(Portion),
+	/// Ounces of a flavour syrup pumped into the cup.
+	Syrup(Syrup, Portion),
+}
+impl Step {
+	/// The ingredient the step puts in the cup, made with the given kind of
+	/// milk if the step uses milk.
+	pub fn ingredient(&self, milk: Milk) -> Ingredient {
+		use Step::*;
+		match self {
+			Espresso(_) => Ingredient::Espresso,
+			SteamedMilk(_) => Ingredient::Milk(milk),
+			Foam(_) => Ingredient::Foam(milk),
+			HotWater(_) => Ingredient::HotWater,
+			Syrup(kind, _) => Ingredient::Syrup(*kind),
+		}
+	}
+	/// How much of the step goes in each size of cup.
+	pub fn portion(&self) -> Portion {
+		use Step::*;
+		match self {
+			Espresso(p) | SteamedMilk(p) | Foam(p) | HotWater(p) | Syrup(_, p) => *p,
+		}
+	}
+	/// The same step with a different portion.
+	pub fn with_portion(&self, portion: Portion) -> Step {
+		use Step::*;
+		match self {
+			Espresso(_) => Espresso(portion),
+			SteamedMilk(_) => SteamedMilk(portion),
+			Foam(_) => Foam(portion),
+			HotWater(_) => HotWater(portion),
+			Syrup(kind, _) => Syrup(*kind, portion),
+		}
+	}
+}
+impl fmt::Display for Step {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use Step::*;
+		match self {
+			Espresso(_) => write!(f, "Espresso"),
+			SteamedMilk(_) => write!(f, "Steamed Milk"),
+			Foam(_) => write!(f, "Foam"),
+			HotWater(_) => write!(f, "Hot Water"),
+			Syrup(kind, _) => write!(f, "{}", kind),
+		}
+	}
+}
+
+/// Describes how to make a drink as branches of steps. The steps in a branch
+/// are done in order, and the branches are made alongside one another, e.g.
+/// a latte pulls its espresso while its milk is being steamed.<br>
+/// The machine only sends an order through the stages its steps need, so an
+/// americano never touches the milk tank. Steamed milk and foam are made with
+/// the recipe's kind of milk, whole milk unless changed with `with_milk`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipe {
+	name: String,
+	milk: Milk,
+	dose: Dose,
+	branches: Vec<Vec<Step>>,
+}
+impl Recipe {
+	pub fn new(name: &str) -> Self {
+		Recipe { name: name.to_string(), milk: Milk::Whole, dose: Dose::STANDARD, branches: Vec::new() }
+	}
+	/// Makes the drink's espresso with the given dose, rather than the
+	/// standard one.
+	pub fn with_dose(mut self, dose: Dose) -> Self {
+		self.dose = dose;
+		self
+	}
+	pub fn dose(&self) -> Dose {
+		self.dose
+	}
+	/// Makes the recipe's steamed milk and foam with the given kind of milk.
+	pub fn with_milk(mut self, milk: Milk) -> Self {
+		self.milk = milk;
+		self
+	}
+	/// Adds a pump of syrup for each size of cup, in a branch of its own.
+	pub fn with_syrup(self, syrup: Syrup) -> Self {
+		self.branch(vec![Step::Syrup(syrup, SYRUP_PORTION)])
+	}
+	/// Changes the portion of every step the function picks, e.g.
+	/// `latte.with_portion(|step| matches!(step, Step::Foam(_)), Portion::each(2.0))`
+	/// for a latte with more foam.
+	pub fn with_portion(mut self, pick: impl Fn(&Step) -> bool, portion: Portion) -> Self {
+		for step in self.branches.iter_mut().flatten().filter(|step| pick(step)) {
+			*step = step.with_portion(portion);
+		}
+		self
+	}
+	/// Adds a branch of steps made in order, alongside the other branches.
+	pub fn branch(mut self, steps: Vec<Step>) -> Self {
+		self.branches.push(steps);
+		self
+	}
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+	pub fn milk_kind(&self) -> Milk {
+		self.milk
+	}
+	pub fn branches(&self) -> &[Vec<Step>] {
+		&self.branches
+	}
+	pub fn steps(&self) -> impl Iterator<Item = &Step> {
+		self.branches.iter().flatten()
+	}
+	/// The ingredients the recipe puts in the cup, in the order its branches
+	/// and steps are listed.
+	pub fn ingredients(&self) -> Vec<Ingredient> {
+		self.steps().map(|step| step.ingredient(self.milk)).collect()
+	}
+	/// Everything the recipe needs measured out for the size of cup, i.e. the
+	/// Measures that come to more than nothing.
+	pub fn needs(&self, s: Size) -> Vec<Measure> {
+		let mut needs = vec![Measure::Beans, Measure::Water, Measure::Shots, Measure::Milk(self.milk), Measure::Foam];
+		needs.extend(Syrup::ALL.iter().map(|kind| Measure::Syrup(*kind)));
+		needs.retain(|m| m.of(self, s) > 0.0);
+		needs
+	}
+	fn total(&self, s: Size, pick: fn(&Step) -> Option<&Portion>) -> f32 {
+		self.steps().filter_map(pick).map(|p| p.of(s)).sum()
+	}
+	/// The shots of espresso pulled for the size of cup.
+	pub fn shots(&self, s: Size) -> f32 {
+		self.total(s, |step| match step { Step::Espresso(p) => Some(p), _ => None })
+	}
+	/// The ounces of beans ground for the size of cup.
+	pub fn beans(&self, s: Size) -> f32 {
+		self.shots(s) * self.dose.beans_per_shot
+	}
+	/// The ounces of water pressed through the grounds for the size of cup.
+	pub fn espresso_water(&self, s: Size) -> f32 {
+		self.shots(s) * self.dose.water_per_shot
+	}
+	/// The ounces of water poured straight into the cup.
+	pub fn hot_water(&self, s: Size) -> f32 {
+		self.total(s, |step| match step { Step::HotWater(p) => Some(p), _ => None })
+	}
+	/// All of the water drawn from the tank for the size of cup.
+	pub fn water(&self, s: Size) -> f32 {
+		self.espresso_water(s) + self.hot_water(s)
+	}
+	/// The ounces of milk frothed for the size of cup.
+	pub fn foam(&self, s: Size) -> f32 {
+		self.total(s, |step| match step { Step::Foam(p) => Some(p), _ => None })
+	}
+	/// All of the milk heated for the size of cup, including the milk that
+	/// will be frothed.
+	pub fn milk(&self, s: Size) -> f32 {
+		self.total(s, |step| match step { Step::SteamedMilk(p) => Some(p), _ => None }) + self.foam(s)
+	}
+	/// The fluid ounces of everything that goes in the cup for its size.
+	pub fn volume(&self, s: Size) -> f32 {
+		self.espresso_water(s) + self.hot_water(s) + self.milk(s) + self.syrups(s)
+	}
+	/// The ounces of a flavour syrup pumped for the size of cup.
+	pub fn syrup(&self, kind: Syrup, s: Size) -> f32 {
+		self.steps()
+			.filter_map(|step| match step { Step::Syrup(k, p) if *k == kind => Some(p.of(s)), _ => None })
+			.sum()
+	}
+	/// All of the syrup pumped for the size of cup.
+	pub fn syrups(&self, s: Size) -> f32 {
+		Syrup::ALL.iter().map(|kind| self.syrup(*kind, s)).sum()
+	}
+}
+impl fmt::Display for Recipe {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name)
+	}
+}
+
+/// A catalogue of named drinks that orders can be placed from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Menu {
+	recipes: Vec<Arc<Recipe>>,
+}
+impl Menu {
+	pub fn new() -> Self {
+		Menu { recipes: Vec::new() }
+	}
+	/// The standard espresso bar menu. Espresso is given in shots and
+	/// everything else in ounces, for a small, medium and large cup.
+	pub fn standard() -> Self {
+		use Step::*;
+		let shots = |s, m, l| Espresso(Portion::new(s, m, l));
+		Menu::new()
+			.with(Recipe::new("Espresso")
+				.branch(vec![shots(1.0, 1.0, 1.0)]))
+			.with(Recipe::new("Doppio")
+				.branch(vec![shots(2.0, 2.0, 2.0)]))
+			.with(Recipe::new("Americano")
+				.branch(vec![shots(1.0, 2.0, 3.0)])
+				.branch(vec![HotWater(Portion::new(6.0, 9.0, 12.0))]))
+			.with(Recipe::new("Latte")
+				.branch(vec![shots(1.0, 2.0, 3.0)])
+				.branch(vec![SteamedMilk(Portion::new(6.0, 9.0, 12.0)), Foam(Portion::each(1.0))]))
+			.with(Recipe::new("Cappuccino")
+				.branch(vec![shots(1.0, 2.0, 3.0)])
+				.branch(vec![SteamedMilk(Portion::new(3.0, 5.0, 6.0)), Foam(Portion::new(3.0, 4.0, 6.0))]))
+			.with(Recipe::new("Macchiato")
+				.branch(vec![shots(1.0, 2.0, 2.0)])
+				.branch(vec![Foam(Portion::each(1.0))]))
+			.with(Recipe::new("Cortado")
+				.branch(vec![shots(2.0, 2.0, 3.0)])
+				.branch(vec![SteamedMilk(Portion::new(2.0, 2.0, 3.0))]))
+			.with(Recipe::new("Flat White")
+				.branch(vec![shots(2.0, 2.0, 3.0)])
+				.branch(vec![SteamedMilk(Portion::new(4.0, 6.0, 8.0)), Foam(Portion::each(0.5))]))
+	}
+	/// Adds a drink to the menu, replacing any drink with the same name in
+	/// its place on the menu.
+	pub fn with(mut self, recipe: Recipe) -> Self {
+		match self.recipes.iter().position(|r| same_name(r.name(), recipe.name())) {
+			Some(i) => self.recipes[i] = Arc::new(recipe),
+			None => self.recipes.push(Arc::new(recipe)),
+		}
+		self
+	}
+	/// Sets every drink on the menu to the given dose, e.g. the dose of the
+	/// machine the menu is ordered from.
+	pub fn with_dose(mut self, dose: Dose) -> Self {
+		self.recipes = self.recipes.iter().map(|r| Arc::new((**r).clone().with_dose(dose))).collect();
+		self
+	}
+	/// Looks up a drink by name. Case, dashes and underscores are ignored, so
+	/// "flat white", "Flat-White" and "FLAT_WHITE" all find the flat white.<br>
+	/// The name can start with a kind of milk and any flavour syrups, e.g.
+	/// "oat latte", "almond milk cappuccino" or "vanilla caramel soy latte",
+	/// in which case the drink is made with that milk and those syrups.
+	pub fn get(&self, name: &str) -> Option<Arc<Recipe>> {
+		if let Some(r) = self.recipe(name) {
+			return Some(r);
+		}
+		let name = name.trim().to_lowercase().replace(['-', '_'], " ");
+		let mut words = name.split_whitespace().peekable();
+		let (mut milk, mut syrups) = (None, Vec::new());
+		while let Some(word) = words.peek() {
+			if let Some(kind) = Milk::ALL.iter().find(|m| same_name(&first_word(m), word)) {
+				milk = Some(*kind);
+				words.next();
+				words.next_if(|w| *w == "milk");
+			} else if let Some(kind) = Syrup::ALL.iter().find(|s| same_name(&first_word(s), word)) {
+				syrups.push(*kind);
+				words.next();
+				words.next_if(|w| *w == "syrup");
+			} else {
+				break;
+			}
+		}
+		if milk.is_none() && syrups.is_empty() {
+			return None;
+		}
+		let rest: Vec<&str> = words.collect();
+		let base = self.recipes.iter().find(|r| same_name(r.name(), &rest.join(" ")))?;
+		let mut recipe = (**base).clone();
+		if let Some(kind) = milk {
+			recipe = recipe.with_milk(kind);
+		}
+		for kind in syrups {
+			recipe = recipe.with_syrup(kind);
+		}
+		Some(Arc::new(recipe))
+	}
+	/// Looks up a drink by the name it is on the menu under, ignoring case,
+	/// dashes and underscores as `get` does, but without a milk or syrups.
+	pub fn recipe(&self, name: &str) -> Option<Arc<Recipe>> {
+		self.recipes.iter().find(|r| same_name(r.name(), name)).map(Arc::clone)
+	}
+	pub fn names(&self) -> impl Iterator<Item = &str> {
+		self.recipes.iter().map(|r| r.name())
+	}
+}
+
+// The first word of a milk or syrup's name, e.g. "oat" for oat milk.
+fn first_word<T: fmt::Display>(t: &T) -> String {
+	t.to_string().split_whitespace().next().unwrap_or_default().to_string()
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+	let normalize = |s: &str| s.trim().to_lowercase().replace(['-', '_'], " ");
+	normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LATTE_SHOTS: Portion = Portion::new(1.0, 2.0, 3.0);
+
+	#[test]
+	fn portion_follows_the_standard_sizes() {
+		assert_eq!(LATTE_SHOTS.of(Size::oz(8.0)), 1.0);
+		assert_eq!(LATTE_SHOTS.of(Size::oz(10.0)), 1.5);
+		assert_eq!(LATTE_SHOTS.of(Size::oz(16.0)), 3.0);
+		assert_eq!(LATTE_SHOTS.of(Size::oz(20.0)), 4.0);
+	}
+
+	#[test]
+	fn portion_below_a_small_is_scaled_down_not_dropped() {
+		assert_eq!(LATTE_SHOTS.of(Size::oz(4.0)), 0.5);
+		assert_eq!(Portion::new(3.0, 4.0, 6.0).of(Size::oz(4.0)), 2.0);
+		assert_eq!(Portion::each(2.0).of(Size::oz(4.0)), 2.0);
+		let menu = Menu::standard();
+		for drink in ["Latte", "Cappuccino", "Americano"] {
+			let recipe = menu.get(drink).unwrap();
+			assert_eq!(recipe.needs(Size::oz(4.0)), recipe.needs(Size::SMALL), "{}", drink);
+		}
+		assert_eq!(LATTE_SHOTS.of(Size::oz(0.0)), 0.0);
+		assert_eq!(LATTE_SHOTS.of(Size::oz(-4.0)), 0.0);
+	}
+
+	#[test]
+	fn portion_above_a_large_keeps_at_least_the_large_amount() {
+		let less_when_larger = Portion::new(2.0, 2.0, 1.0);
+		assert_eq!(less_when_larger.of(Size::oz(16.0)), 1.0);
+		assert_eq!(less_when_larger.of(Size::oz(20.0)), 1.0);
+		assert_eq!(Portion::each(1.0).of(Size::oz(20.0)), 1.0);
+	}
+}
@@ -0,0 +1,469 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::machine_components::SizeTable;
+use crate::message_based::Cup;
+use crate::recipe::Menu;
+
+/// A stage of a machine's pipelines, named after the stage in message_based
+/// that does the same job.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+	GrindCoffee,
+	DispenseWater,
+	PressEspresso,
+	HeatMilk,
+	FrothMilk,
+	PourWater,
+	PumpSyrup,
+	AssembleCups,
+}
+impl Stage {
+	pub const ALL: [Stage; 8] = [
+		Stage::GrindCoffee,
+		Stage::DispenseWater,
+		Stage::PressEspresso,
+		Stage::HeatMilk,
+		Stage::FrothMilk,
+		Stage::PourWater,
+		Stage::PumpSyrup,
+		Stage::AssembleCups,
+	];
+
+	pub fn name(&self) -> &'static str {
+		use Stage::*;
+		match self {
+			GrindCoffee => "grind_coffee",
+			DispenseWater => "dispense_water",
+			PressEspresso => "press_espresso",
+			HeatMilk => "heat_milk",
+			FrothMilk => "froth_milk",
+			PourWater => "pour_water",
+			PumpSyrup => "pump_syrup",
+			AssembleCups => "assemble_cups",
+		}
+	}
+
+	// The first stage of each branch of the machine the cup needs.
+	fn starts(cup: &Cup) -> Vec<Stage> {
+		let (r, s) = (cup.recipe(), cup.size());
+		let branches = [
+			(Stage::GrindCoffee, r.shots(s)),
+			(Stage::HeatMilk, r.milk(s)),
+			(Stage::PourWater, r.hot_water(s)),
+			(Stage::PumpSyrup, r.syrups(s)),
+		];
+		branches.iter().filter(|(_, amount)| *amount > 0.0).map(|(stage, _)| *stage).collect()
+	}
+
+	// The stage the cup goes to after this one on the same branch, if any.
+	fn next(&self, cup: &Cup) -> Option<Stage> {
+		match self {
+			Stage::GrindCoffee => Some(Stage::DispenseWater),
+			Stage::DispenseWater => Some(Stage::PressEspresso),
+			Stage::HeatMilk if cup.recipe().foam(cup.size()) > 0.0 => Some(Stage::FrothMilk),
+			_ => None,
+		}
+	}
+}
+impl fmt::Display for Stage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+/// How long each stage of a machine takes over one cup. The defaults are the
+/// times the stages take in ingredient_based, e.g. 1000 ms to heat the water.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timings {
+	pub grind_coffee: Duration,
+	pub dispense_water: Duration,
+	pub press_espresso: Duration,
+	pub heat_milk: Duration,
+	pub froth_milk: Duration,
+	pub pour_water: Duration,
+	pub pump_syrup: Duration,
+	pub assemble_cups: Duration,
+}
+impl Default for Timings {
+	fn default() -> Self {
+		Timings {
+			grind_coffee: Duration::from_millis(500),
+			dispense_water: Duration::from_millis(1000),
+			press_espresso: Duration::from_millis(500),
+			heat_milk: Duration::from_millis(750),
+			froth_milk: Duration::from_millis(500),
+			pour_water: Duration::from_millis(250),
+			pump_syrup: Duration::from_millis(250),
+			assemble_cups: Duration::from_millis(250),
+		}
+	}
+}
+impl Timings {
+	/// Sets how long a stage takes over one cup.
+	pub fn with(mut self, stage: Stage, duration: Duration) -> Self {
+		use Stage::*;
+		let time = match stage {
+			GrindCoffee => &mut self.grind_coffee,
+			DispenseWater => &mut self.dispense_water,
+			PressEspresso => &mut self.press_espresso,
+			HeatMilk => &mut self.heat_milk,
+			FrothMilk => &mut self.froth_milk,
+			PourWater => &mut self.pour_water,
+			PumpSyrup => &mut self.pump_syrup,
+			AssembleCups => &mut self.assemble_cups,
+		};
+		*time = duration;
+		self
+	}
+	pub fn of(&self, stage: Stage) -> Duration {
+		use Stage::*;
+		match stage {
+			GrindCoffee => self.grind_coffee,
+			DispenseWater => self.dispense_water,
+			PressEspresso => self.press_espresso,
+			HeatMilk => self.heat_milk,
+			FrothMilk => self.froth_milk,
+			PourWater => self.pour_water,
+			PumpSyrup => self.pump_syrup,
+			AssembleCups => self.assemble_cups,
+		}
+	}
+
+	/// How long a cup takes on a machine with nothing else to make, i.e. its
+	/// longest branch and then assembling it.
+	pub fn unhurried(&self, cup: &Cup) -> Duration {
+		let branch = |start: Stage| {
+			let mut stage = Some(start);
+			let mut took = Duration::ZERO;
+			while let Some(s) = stage {
+				took += self.of(s);
+				stage = s.next(cup);
+			}
+			took
+		};
+		Stage::starts(cup).into_iter().map(branch).max().unwrap_or_default() + self.assemble_cups
+	}
+}
+
+/// A day of orders at random, arriving at an average rate from the given
+/// seed. Each is for a drink on the menu in a size from the table, picked
+/// evenly, and the customers are numbered in the order they arrive. A rate or
+/// a length of day that isn't a finite number gives no orders.
+pub fn arrivals(seed: u64, orders_per_hour: f32, hours: f32, menu: &Menu, sizes: &SizeTable) -> Vec<(Duration, Cup)> {
+	let mut rng = StdRng::seed_from_u64(seed);
+	let drinks: Vec<&str> = menu.names().collect();
+	let sizes: Vec<_> = sizes.iter().map(|(_, s)| s).collect();
+	let mut arrivals = Vec::new();
+	if drinks.is_empty() || sizes.is_empty() || !orders_per_hour.is_finite() || !hours.is_finite() || orders_per_hour <= 0.0 {
+		return arrivals;
+	}
+	let end = hours * 3600.0;
+	let mut at = 0.0;
+	loop {
+		// the time between arrivals is exponential for a steady rate.
+		at += -(1.0 - rng.gen::<f32>()).ln() * 3600.0 / orders_per_hour;
+		if at >= end {
+			return arrivals;
+		}
+		let drink = drinks[rng.gen_range(0..drinks.len())];
+		let size = sizes[rng.gen_range(0..sizes.len())];
+		let client = format!("Customer {}", arrivals.len() + 1);
+		if let Some(cup) = Cup::order(menu, drink, size, client) {
+			arrivals.push((Duration::from_secs_f32(at), cup));
+		}
+	}
+}
+
+/// How one order of a shift went.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderTimes {
+	pub client: String,
+	pub machine: usize,
+	pub arrived: Duration,
+	pub ready: Duration,
+	/// The time the order spent waiting for stages that were busy with other
+	/// orders, i.e. how much longer it took than on a machine of its own.
+	pub waited: Duration,
+}
+impl OrderTimes {
+	/// The time from the order arriving to it being ready.
+	pub fn turnaround(&self) -> Duration {
+		self.ready - self.arrived
+	}
+}
+
+/// How busy a stage of one of the machines was over a shift.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StageUse {
+	pub machine: usize,
+	pub stage: Stage,
+	pub busy: Duration,
+	pub jobs: usize,
+}
+
+/// What came of a shift: the times of every order and how busy each stage
+/// was. The shift runs from the start of the schedule to the last order being
+/// ready.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+	pub machines: usize,
+	pub orders: Vec<OrderTimes>,
+	pub stages: Vec<StageUse>,
+	pub length: Duration,
+}
+impl Report {
+	/// The orders made per hour of the shift.
+	pub fn throughput(&self) -> f32 {
+		if self.length.is_zero() {
+			return 0.0;
+		}
+		self.orders.len() as f32 * 3600.0 / self.length.as_secs_f32()
+	}
+	pub fn mean_wait(&self) -> Duration {
+		mean(self.orders.iter().map(|o| o.waited))
+	}
+	pub fn max_wait(&self) -> Duration {
+		self.orders.iter().map(|o| o.waited).max().unwrap_or_default()
+	}
+	pub fn mean_turnaround(&self) -> Duration {
+		mean(self.orders.iter().map(OrderTimes::turnaround))
+	}
+	pub fn max_turnaround(&self) -> Duration {
+		self.orders.iter().map(OrderTimes::turnaround).max().unwrap_or_default()
+	}
+	/// The share of the shift the stage of the machine was busy, from 0 to 1.
+	pub fn utilisation(&self, machine: usize, stage: Stage) -> f32 {
+		match self.stages.iter().find(|u| u.machine == machine && u.stage == stage) {
+			Some(u) if !self.length.is_zero() => u.busy.as_secs_f32() / self.length.as_secs_f32(),
+			_ => 0.0,
+		}
+	}
+}
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{} orders on {} machines in {:.1} min", self.orders.len(), self.machines, self.length.as_secs_f32() / 60.0)?;
+		writeln!(f, "Throughput: {:.1} orders per hour", self.throughput())?;
+		writeln!(f, "Wait: {:.1} s mean, {:.1} s max", self.mean_wait().as_secs_f32(), self.max_wait().as_secs_f32())?;
+		writeln!(f, "Turnaround: {:.1} s mean, {:.1} s max", self.mean_turnaround().as_secs_f32(), self.max_turnaround().as_secs_f32())?;
+		write!(f, "Stage utilisation:")?;
+		for u in &self.stages {
+			write!(f, "\n  Machine {} {}: {:.1}% ({} cups)", u.machine + 1, u.stage, self.utilisation(u.machine, u.stage) * 100.0, u.jobs)?;
+		}
+		Ok(())
+	}
+}
+
+fn mean(durations: impl Iterator<Item = Duration>) -> Duration {
+	let (total, count) = durations.fold((Duration::ZERO, 0), |(total, count), d| (total + d, count + 1));
+	if count == 0 { Duration::ZERO } else { total / count }
+}
+
+// Something that happens at a point in the shift.
+#[derive(Copy, Clone)]
+enum Event {
+	Arrive(usize),
+	Finish(usize, Stage, usize),
+}
+
+// The events still to happen. They are handled in the order of their times,
+// and in the order they were scheduled for the same time.
+#[derive(Default)]
+struct Agenda {
+	events: Vec<Event>,
+	queue: BinaryHeap<Reverse<(Duration, usize)>>,
+}
+impl Agenda {
+	fn schedule(&mut self, at: Duration, event: Event) {
+		self.queue.push(Reverse((at, self.events.len())));
+		self.events.push(event);
+	}
+	fn next(&mut self) -> Option<(Duration, Event)> {
+		self.queue.pop().map(|Reverse((at, e))| (at, self.events[e]))
+	}
+}
+
+// One stage of one machine: the cups waiting for it, and whether it is busy
+// with a cup.
+#[derive(Default)]
+struct Server {
+	queue: VecDeque<usize>,
+	busy: bool,
+	busy_for: Duration,
+	jobs: usize,
+}
+
+/// A discrete-event simulation of a shift on some number of machines. Every
+/// stage of a machine makes one cup at a time and the cups waiting for it
+/// queue up in the order they reach it. Each order goes to the machine with
+/// the fewest orders in progress when it arrives. The simulation runs on its
+/// own time, so a day's shift takes no time on the wall.
+pub struct Shift {
+	machines: usize,
+	timings: Timings,
+}
+impl Shift {
+	pub fn new(machines: usize) -> Self {
+		Shift { machines: machines.max(1), timings: Timings::default() }
+	}
+	pub fn with_timings(mut self, timings: Timings) -> Self {
+		self.timings = timings;
+		self
+	}
+
+	/// Runs the shift over the schedule of orders and the times they arrive.
+	pub fn run(&self, schedule: &[(Duration, Cup)]) -> Report {
+		let mut agenda = Agenda::default();
+		let mut servers: Vec<Vec<Server>> = (0..self.machines)
+			.map(|_| Stage::ALL.iter().map(|_| Server::default()).collect())
+			.collect();
+		let mut in_progress = vec![0; self.machines];
+		let mut machine_of = vec![0; schedule.len()];
+		let mut branches_left = vec![0; schedule.len()];
+		let mut ready = vec![None; schedule.len()];
+		for (i, (at, _)) in schedule.iter().enumerate() {
+			agenda.schedule(*at, Event::Arrive(i));
+		}
+
+		let index = |stage: Stage| Stage::ALL.iter().position(|s| *s == stage).unwrap();
+		while let Some((now, event)) = agenda.next() {
+			// the stages that a cup reaches at this time.
+			let mut reached: Vec<(usize, Stage, usize)> = Vec::new();
+			match event {
+				Event::Arrive(o) => {
+					let m = (0..self.machines).min_by_key(|m| in_progress[*m]).unwrap();
+					in_progress[m] += 1;
+					machine_of[o] = m;
+					let starts = Stage::starts(&schedule[o].1);
+					branches_left[o] = starts.len();
+					if starts.is_empty() {
+						reached.push((m, Stage::AssembleCups, o));
+					}
+					reached.extend(starts.into_iter().map(|stage| (m, stage, o)));
+				}
+				Event::Finish(m, stage, o) => {
+					let server = &mut servers[m][index(stage)];
+					server.busy = false;
+					server.busy_for += self.timings.of(stage);
+					server.jobs += 1;
+					if let Some(next) = stage.next(&schedule[o].1) {
+						reached.push((m, next, o));
+					} else if stage == Stage::AssembleCups {
+						ready[o] = Some(now);
+						in_progress[m] -= 1;
+					} else {
+						branches_left[o] -= 1;
+						if branches_left[o] == 0 {
+							reached.push((m, Stage::AssembleCups, o));
+						}
+					}
+					// the stage moves on to the next cup waiting for it.
+					reached.push((m, stage, usize::MAX));
+				}
+			}
+			for (m, stage, o) in reached {
+				let server = &mut servers[m][index(stage)];
+				if o != usize::MAX {
+					server.queue.push_back(o);
+				}
+				if !server.busy {
+					if let Some(next) = server.queue.pop_front() {
+						server.busy = true;
+						agenda.schedule(now + self.timings.of(stage), Event::Finish(m, stage, next));
+					}
+				}
+			}
+		}
+
+		let orders: Vec<OrderTimes> = schedule.iter().enumerate()
+			.filter_map(|(o, (arrived, cup))| ready[o].map(|ready| OrderTimes {
+				client: cup.client().to_string(),
+				machine: machine_of[o],
+				arrived: *arrived,
+				ready,
+				waited: (ready - *arrived).saturating_sub(self.timings.unhurried(cup)),
+			}))
+			.collect();
+		let stages = servers.iter().enumerate()
+			.flat_map(|(m, stages)| stages.iter().zip(Stage::ALL.iter()).map(move |(server, stage)| StageUse {
+				machine: m,
+				stage: *stage,
+				busy: server.busy_for,
+				jobs: server.jobs,
+			}))
+			.collect();
+		Report {
+			machines: self.machines,
+			length: orders.iter().map(|o| o.ready).max().unwrap_or_default(),
+			orders,
+			stages,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::machine_components::Size;
+
+	fn latte(client: &str) -> Cup {
+		Cup::order(&Menu::standard(), "Latte", Size::MEDIUM, client.to_string()).unwrap()
+	}
+
+	#[test]
+	fn a_lone_order_takes_its_longest_branch_and_assembling() {
+		let report = Shift::new(1).run(&[(Duration::ZERO, latte("Josh"))]);
+		// grinding, dispensing and pressing outlast heating and frothing.
+		assert_eq!(report.orders[0].ready, Duration::from_millis(2250));
+		assert_eq!(report.orders[0].waited, Duration::ZERO);
+		assert_eq!(report.length, Duration::from_millis(2250));
+		assert_eq!(report.utilisation(0, Stage::DispenseWater), 1000.0 / 2250.0);
+		assert_eq!(report.utilisation(0, Stage::PourWater), 0.0);
+	}
+
+	#[test]
+	fn orders_queue_for_a_busy_stage() {
+		let schedule = [(Duration::ZERO, latte("Josh")), (Duration::ZERO, latte("Sharon"))];
+		let report = Shift::new(1).run(&schedule);
+		assert_eq!(report.orders[1].client, "Sharon");
+		// the second latte waits for the grinder and then for the water.
+		assert_eq!(report.orders[1].ready, Duration::from_millis(3250));
+		assert_eq!(report.orders[1].waited, Duration::from_millis(1000));
+		assert_eq!(report.max_wait(), Duration::from_millis(1000));
+		let jobs: usize = report.stages.iter().filter(|u| u.stage == Stage::GrindCoffee).map(|u| u.jobs).sum();
+		assert_eq!(jobs, 2);
+	}
+
+	#[test]
+	fn orders_are_shared_between_machines() {
+		let schedule = [(Duration::ZERO, latte("Josh")), (Duration::ZERO, latte("Sharon"))];
+		let report = Shift::new(2).run(&schedule);
+		assert_eq!(report.orders.iter().map(|o| o.machine).collect::<Vec<_>>(), vec![0, 1]);
+		assert_eq!(report.max_wait(), Duration::ZERO);
+		assert_eq!(report.throughput(), 2.0 * 3600.0 / 2.25);
+	}
+
+	#[test]
+	fn arrivals_are_the_same_for_a_seed() {
+		let (menu, sizes) = (Menu::standard(), SizeTable::standard());
+		let day = arrivals(7, 60.0, 2.0, &menu, &sizes);
+		assert!(!day.is_empty());
+		assert!(day.windows(2).all(|w| w[0].0 <= w[1].0));
+		assert!(day.iter().all(|(at, _)| *at < Duration::from_secs(2 * 3600)));
+		let again = arrivals(7, 60.0, 2.0, &menu, &sizes);
+		let clients = |day: &[(Duration, Cup)]| day.iter().map(|(at, cup)| (*at, cup.to_string())).collect::<Vec<_>>();
+		assert_eq!(clients(&day), clients(&again));
+	}
+
+	#[test]
+	fn arrivals_need_a_finite_rate_and_day() {
+		let (menu, sizes) = (Menu::standard(), SizeTable::standard());
+		assert!(arrivals(1, f32::INFINITY, 1.0, &menu, &sizes).is_empty());
+		assert!(arrivals(1, f32::NAN, 1.0, &menu, &sizes).is_empty());
+		assert!(arrivals(1, 60.0, f32::INFINITY, &menu, &sizes).is_empty());
+		assert!(arrivals(1, 0.0, 1.0, &menu, &sizes).is_empty());
+	}
+}
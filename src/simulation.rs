@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+thread_local! {
+	// The stream of the simulation the thread is running in, if any.
+	static CURRENT: RefCell<Option<Stream>> = const { RefCell::new(None) };
+}
+
+/// Where a machine gets its time from.
+pub trait Clock: Send + Sync {
+	/// The time since the clock started.
+	fn now(&self) -> time::Duration;
+	/// Waits for the duration to pass on the clock.
+	fn sleep(&self, duration: time::Duration);
+	/// A clock that starts at this one's time and moves on by itself from
+	/// then on.
+	fn split(&self) -> Arc<dyn Clock>;
+}
+
+/// The time on the wall. Sleeping blocks the thread.
+pub struct SystemClock {
+	start: time::Instant,
+}
+impl Default for SystemClock {
+	fn default() -> Self {
+		SystemClock::new()
+	}
+}
+impl SystemClock {
+	pub fn new() -> Self {
+		SystemClock { start: time::Instant::now() }
+	}
+}
+impl Clock for SystemClock {
+	fn now(&self) -> time::Duration {
+		self.start.elapsed()
+	}
+	fn sleep(&self, duration: time::Duration) {
+		thread::sleep(duration);
+	}
+	// every clock on the wall moves on together.
+	fn split(&self) -> Arc<dyn Clock> {
+		Arc::new(SystemClock { start: self.start })
+	}
+}
+
+/// A clock that moves on by every sleep straight away, on whichever thread
+/// the sleep is, so that a run takes no time on the wall. A split of it moves
+/// on only by its own sleeps.
+#[derive(Default)]
+pub struct VirtualClock {
+	elapsed: Mutex<time::Duration>,
+}
+impl VirtualClock {
+	pub fn new() -> Self {
+		VirtualClock::default()
+	}
+}
+impl Clock for VirtualClock {
+	fn now(&self) -> time::Duration {
+		*self.elapsed.lock().unwrap()
+	}
+	fn sleep(&self, duration: time::Duration) {
+		*self.elapsed.lock().unwrap() += duration;
+	}
+	fn split(&self) -> Arc<dyn Clock> {
+		Arc::new(VirtualClock { elapsed: Mutex::new(self.now()) })
+	}
+}
+
+/// The clock and randomness a machine runs with. A real simulation runs on
+/// the wall clock with unseeded randomness. A seeded one runs on a
+/// VirtualClock and draws every random number from its seed, so that a run
+/// can be replayed, e.g. to get the same component timing out on the same
+/// order again.
+#[derive(Clone)]
+pub struct Simulation {
+	clock: Arc<dyn Clock>,
+	seed: Option<u64>,
+	// the clocks of the streams split from the simulation's clock.
+	splits: Arc<Mutex<Vec<Arc<dyn Clock>>>>,
+}
+impl Default for Simulation {
+	fn default() -> Self {
+		Simulation::real()
+	}
+}
+impl Simulation {
+	pub fn real() -> Self {
+		Simulation { clock: Arc::new(SystemClock::new()), seed: None, splits: Arc::default() }
+	}
+	pub fn seeded(seed: u64) -> Self {
+		Simulation { clock: Arc::new(VirtualClock::new()), seed: Some(seed), splits: Arc::default() }
+	}
+	/// Runs the simulation on another clock, e.g. a seeded simulation on the
+	/// wall clock to watch it play out.
+	pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+		self.clock = clock;
+		self
+	}
+	pub fn seed(&self) -> Option<u64> {
+		self.seed
+	}
+	pub fn clock(&self) -> &Arc<dyn Clock> {
+		&self.clock
+	}
+	/// The furthest time the simulation's clock or the clock of any of its
+	/// streams has reached, e.g. when the last stage finished its last order.
+	pub fn elapsed(&self) -> time::Duration {
+		self.splits.lock().unwrap().iter()
+			.map(|clock| clock.now())
+			.fold(self.clock.now(), time::Duration::max)
+	}
+	/// A stream of the simulation for one stage of the machine to run in,
+	/// on a split of the simulation's clock, so that the time a stage spends
+	/// on an order only holds up that stage. When seeded, the stream's numbers
+	/// come from the seed and the name of the stream, so that the stage draws
+	/// the same numbers in every run with that seed.
+	pub fn stream(&self, name: &str) -> Stream {
+		let clock = self.clock.split();
+		self.splits.lock().unwrap().push(Arc::clone(&clock));
+		Stream { clock, rng: self.rng(name) }
+	}
+	/// A stream of the simulation like `stream`, but on the simulation's own
+	/// clock, e.g. for the checks orders are submitted after.
+	pub fn shared_stream(&self, name: &str) -> Stream {
+		Stream { clock: Arc::clone(&self.clock), rng: self.rng(name) }
+	}
+	fn rng(&self, name: &str) -> Option<Arc<Mutex<StdRng>>> {
+		self.seed.map(|seed| Arc::new(Mutex::new(StdRng::seed_from_u64(seed ^ fnv1a(name)))))
+	}
+}
+
+/// The clock and the numbers of a simulation that one thread runs in.
+#[derive(Clone)]
+pub struct Stream {
+	clock: Arc<dyn Clock>,
+	rng: Option<Arc<Mutex<StdRng>>>,
+}
+impl Stream {
+	/// Runs the function in the stream, i.e. with `sleep` and `gen_range`
+	/// going to the stream's clock and numbers.
+	pub fn enter<T>(&self, f: impl FnOnce() -> T) -> T {
+		let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+		let result = f();
+		CURRENT.with(|current| *current.borrow_mut() = previous);
+		result
+	}
+}
+
+/// Sleeps on the clock of the simulation the thread is running in, or on the
+/// wall clock outside of one.
+pub fn sleep(duration: time::Duration) {
+	let clock = CURRENT.with(|current| current.borrow().as_ref().map(|s| Arc::clone(&s.clock)));
+	match clock {
+		Some(clock) => clock.sleep(duration),
+		None => thread::sleep(duration),
+	}
+}
+
+/// The time on the clock of the simulation the thread is running in, or None
+/// outside of one.
+pub fn now() -> Option<time::Duration> {
+	CURRENT.with(|current| current.borrow().as_ref().map(|s| s.clock.now()))
+}
+
+/// Waits on the clock of the simulation the thread is running in until it
+/// reaches the time, e.g. the time an order was passed on to a stage. A clock
+/// already past it doesn't wait, nor does a thread outside of a simulation.
+pub fn wait_until(at: time::Duration) {
+	let clock = CURRENT.with(|current| current.borrow().as_ref().map(|s| Arc::clone(&s.clock)));
+	if let Some(clock) = clock {
+		let now = clock.now();
+		if at > now {
+			clock.sleep(at - now);
+		}
+	}
+}
+
+/// A random number in the range, from the simulation the thread is running
+/// in, or unseeded outside of a seeded one.
+pub fn gen_range(range: Range<u64>) -> u64 {
+	let rng = CURRENT.with(|current| current.borrow().as_ref().and_then(|s| s.rng.clone()));
+	match rng {
+		Some(rng) => rng.lock().unwrap().gen_range(range),
+		None => thread_rng().gen_range(range),
+	}
+}
+
+// A hash of the name that is the same on every platform and every build, so
+// that a seed gives the same streams wherever it is replayed.
+fn fnv1a(name: &str) -> u64 {
+	name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
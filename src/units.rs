@@ -0,0 +1,185 @@
+use std::fmt;
+use std::ops;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Grams in an ounce of weight.
+const GRAMS_PER_OUNCE: f32 = 28.349_524;
+// Millilitres in a US fluid ounce.
+const MILLILITRES_PER_FLUID_OUNCE: f32 = 29.573_53;
+
+// Whether quantities are displayed in metric units. Imperial by default.
+static METRIC: AtomicBool = AtomicBool::new(false);
+
+/// The units quantities are displayed in. Quantities are stored the same way
+/// whichever system is used; only their Display output changes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnitSystem {
+	/// Ounces, fluid ounces and degrees Fahrenheit.
+	Imperial,
+	/// Grams, millilitres and degrees Celsius.
+	Metric,
+}
+
+/// Sets the units every quantity is displayed in, for the whole program.
+pub fn set_unit_system(system: UnitSystem) {
+	METRIC.store(system == UnitSystem::Metric, Ordering::Relaxed);
+}
+
+pub fn unit_system() -> UnitSystem {
+	if METRIC.load(Ordering::Relaxed) {
+		UnitSystem::Metric
+	} else {
+		UnitSystem::Imperial
+	}
+}
+
+// Rounds to a tenth so that converted amounts print tidily.
+fn tenths(value: f32) -> f32 {
+	(value * 10.0).round() / 10.0
+}
+
+/// A weight, e.g. of coffee beans.
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct Mass {
+	ounces: f32,
+}
+impl Mass {
+	pub const fn ounces(ounces: f32) -> Self {
+		Mass { ounces }
+	}
+	pub const fn grams(grams: f32) -> Self {
+		Mass { ounces: grams / GRAMS_PER_OUNCE }
+	}
+	pub fn as_ounces(&self) -> f32 {
+		self.ounces
+	}
+	pub fn as_grams(&self) -> f32 {
+		self.ounces * GRAMS_PER_OUNCE
+	}
+}
+impl fmt::Display for Mass {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match unit_system() {
+			UnitSystem::Imperial => write!(f, "{} oz.", tenths(self.as_ounces())),
+			UnitSystem::Metric => write!(f, "{} g", tenths(self.as_grams())),
+		}
+	}
+}
+
+/// A volume of liquid, e.g. of milk or the size of a cup.
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct Volume {
+	fluid_ounces: f32,
+}
+impl Volume {
+	pub const fn fluid_ounces(fluid_ounces: f32) -> Self {
+		Volume { fluid_ounces }
+	}
+	pub const fn millilitres(millilitres: f32) -> Self {
+		Volume { fluid_ounces: millilitres / MILLILITRES_PER_FLUID_OUNCE }
+	}
+	pub fn as_fluid_ounces(&self) -> f32 {
+		self.fluid_ounces
+	}
+	pub fn as_millilitres(&self) -> f32 {
+		self.fluid_ounces * MILLILITRES_PER_FLUID_OUNCE
+	}
+}
+impl fmt::Display for Volume {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match unit_system() {
+			UnitSystem::Imperial => write!(f, "{} fl. oz.", tenths(self.as_fluid_ounces())),
+			UnitSystem::Metric => write!(f, "{} ml", tenths(self.as_millilitres())),
+		}
+	}
+}
+
+/// This macro implements adding, subtracting and scaling for a quantity
+/// newtype, so that e.g. two masses can be added together. It takes the
+/// type of the quantity and the name of the field holding its value.
+macro_rules! quantity_ops {
+	($t: ty, $field: ident) => {
+		impl ops::Add for $t {
+			type Output = Self;
+			fn add(self, other: Self) -> Self {
+				Self { $field: self.$field + other.$field }
+			}
+		}
+		impl ops::Sub for $t {
+			type Output = Self;
+			fn sub(self, other: Self) -> Self {
+				Self { $field: self.$field - other.$field }
+			}
+		}
+		impl ops::Mul<f32> for $t {
+			type Output = Self;
+			fn mul(self, by: f32) -> Self {
+				Self { $field: self.$field * by }
+			}
+		}
+	};
+}
+quantity_ops!(Mass, ounces);
+quantity_ops!(Volume, fluid_ounces);
+
+/// A temperature, e.g. of water heated for espresso.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Temperature {
+	fahrenheit: f32,
+}
+impl Temperature {
+	pub const fn fahrenheit(fahrenheit: f32) -> Self {
+		Temperature { fahrenheit }
+	}
+	pub const fn celsius(celsius: f32) -> Self {
+		Temperature { fahrenheit: celsius * 9.0 / 5.0 + 32.0 }
+	}
+	pub fn as_fahrenheit(&self) -> f32 {
+		self.fahrenheit
+	}
+	pub fn as_celsius(&self) -> f32 {
+		(self.fahrenheit - 32.0) * 5.0 / 9.0
+	}
+}
+impl fmt::Display for Temperature {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match unit_system() {
+			UnitSystem::Imperial => write!(f, "{} °F", tenths(self.as_fahrenheit())),
+			UnitSystem::Metric => write!(f, "{} °C", tenths(self.as_celsius())),
+		}
+	}
+}
+
+/// What a hopper or tank measures its material in: beans by weight and
+/// liquids by volume.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+	/// Ounces of weight.
+	Ounces,
+	/// Fluid ounces of volume.
+	FluidOunces,
+}
+impl Unit {
+	/// The amount of material that a number of this unit comes to.
+	pub fn amount(&self, value: f32) -> Amount {
+		match self {
+			Unit::Ounces => Amount::Mass(Mass::ounces(value)),
+			Unit::FluidOunces => Amount::Volume(Volume::fluid_ounces(value)),
+		}
+	}
+}
+
+/// An amount of material, as a weight or a volume depending on the material.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Amount {
+	Mass(Mass),
+	Volume(Volume),
+}
+impl fmt::Display for Amount {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Amount::Mass(m) => write!(f, "{}", m),
+			Amount::Volume(v) => write!(f, "{}", v),
+		}
+	}
+}